@@ -0,0 +1,178 @@
+use std::fmt;
+
+use super::Location;
+use crate::instruction::Opcode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssemblerError {
+    /// Text that is not part of an instruction or directive, such as trailing input
+    UnexpectedInput { input: String },
+    /// An instruction whose mnemonic is not an opcode
+    UnknownMnemonic { name: String },
+    /// A `$` operand that is not one of the VM's registers
+    BadRegister { register: String },
+    /// A label that was already declared
+    DuplicateLabel { name: String },
+    /// An instruction was found without an opcode in its opcode field
+    NonOpcodeInOpcodeField,
+    /// A token that cannot be encoded was used as an operand
+    InvalidOperand { token: String },
+    /// An `@label` operand refers to a label that was never declared
+    UndefinedLabel { name: String },
+    /// A data label was used where a code address is expected, such as `call @msg`
+    NotACodeLabel { name: String },
+    /// A code label was used where read-only data is expected, such as `prts @main`
+    NotADataLabel { name: String },
+    /// The operands of an instruction do not match the ones its opcode takes
+    IncorrectOperands { opcode: Opcode },
+    /// An integer or label address is too large or small for the operand it is used as
+    IntegerOutOfRange { value: i64, min: i64, max: i64 },
+    /// A directive name the assembler does not know
+    UnknownDirective { name: String },
+    /// A data directive such as `.asciiz` was used outside of the `.data` section
+    DirectiveOutsideDataSection { name: String },
+    /// An instruction was found in the `.data` section
+    InstructionInDataSection,
+    /// A data directive was given operands it cannot store
+    IncorrectDirectiveOperands { name: String },
+    /// The read-only data grew past what fits in front of the code while keeping every
+    /// address within reach of a two-byte operand
+    DataSectionTooLarge { length: u64, max: usize },
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssemblerError::UnexpectedInput { input } => write!(f, "unexpected `{}`", input),
+            AssemblerError::UnknownMnemonic { name } => write!(f, "unknown mnemonic `{}`", name),
+            AssemblerError::BadRegister { register } => {
+                write!(f, "`{}` is not a register", register)
+            }
+            AssemblerError::DuplicateLabel { name } => {
+                write!(f, "label `{}` is already declared", name)
+            }
+            AssemblerError::NonOpcodeInOpcodeField => {
+                write!(f, "non-opcode found in opcode field")
+            }
+            AssemblerError::InvalidOperand { token } => write!(f, "invalid operand: {}", token),
+            AssemblerError::UndefinedLabel { name } => write!(f, "undefined label: @{}", name),
+            AssemblerError::NotACodeLabel { name } => write!(f, "@{} is not a code label", name),
+            AssemblerError::NotADataLabel { name } => write!(f, "@{} is not a data label", name),
+            AssemblerError::UnknownDirective { name } => write!(f, "unknown directive .{}", name),
+            AssemblerError::DirectiveOutsideDataSection { name } => {
+                write!(f, ".{} must be in the .data section", name)
+            }
+            AssemblerError::InstructionInDataSection => {
+                write!(f, "instructions must be in the .code section")
+            }
+            AssemblerError::IncorrectDirectiveOperands { name } => {
+                write!(f, "invalid operands for .{}", name)
+            }
+            AssemblerError::DataSectionTooLarge { length, max } => write!(
+                f,
+                "read-only data grows to {} bytes, more than the {} that fit in front of the code",
+                length, max
+            ),
+            AssemblerError::IntegerOutOfRange { value, min, max } => {
+                write!(f, "{} is out of range ({} to {})", value, min, max)
+            }
+            AssemblerError::IncorrectOperands { opcode } => {
+                // Opcodes with an alternate form, such as ADD and ADDI, accept the operands
+                // of either
+                let signatures: Vec<String> = std::iter::once(*opcode)
+                    .chain(opcode.alternate_form())
+                    .map(|code| {
+                        let kinds: Vec<String> =
+                            code.operands().iter().map(|o| o.to_string()).collect();
+                        kinds.join(", ")
+                    })
+                    .collect();
+                if signatures == [""] {
+                    write!(f, "{} takes no operands", opcode)
+                } else {
+                    write!(
+                        f,
+                        "{} expects operands: {}",
+                        opcode,
+                        signatures.join(" or ")
+                    )
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// An `AssemblerError` together with where it happened, displayed as a diagnostic with
+/// the offending source line and a caret under the column:
+///
+/// ```text
+/// program.iasm:2:1: error: unknown mnemonic `lod`
+///   |
+/// 2 | lod $0 #1
+///   | ^
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    /// Name of the file being assembled, `<input>` if it did not come from a file
+    pub file: String,
+    pub location: Location,
+    /// The source line `location` points into
+    pub source_line: String,
+    pub error: AssemblerError,
+}
+
+impl AssembleError {
+    pub fn new(error: AssemblerError, location: Location, source: &str) -> AssembleError {
+        let source_line = source
+            .lines()
+            .nth(location.line.saturating_sub(1))
+            .unwrap_or("")
+            .to_string();
+        AssembleError {
+            file: "<input>".to_string(),
+            location,
+            source_line,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.location.line.to_string().len());
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.location.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, "{}:{}: error: {}", self.file, self.location, self.error)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.location.line, self.source_line)?;
+        write!(f, "{} | {}^", gutter, indent)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_snippet() {
+        let error = AssembleError::new(
+            AssemblerError::BadRegister {
+                register: "$40".to_string(),
+            },
+            Location { line: 2, column: 9 },
+            "hlt\nload $0 $40\n",
+        );
+        assert_eq!(
+            error.to_string(),
+            "<input>:2:9: error: `$40` is not a register\n  |\n2 | load $0 $40\n  |         ^"
+        );
+    }
+}
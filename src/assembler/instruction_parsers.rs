@@ -1,13 +1,12 @@
+use super::assembler_errors::AssemblerError;
 use super::directive_parsers::directive;
 use super::label_parsers::label_declaration;
 use super::opcode_parsers::opcode;
 use super::operand_parsers::operand;
-use super::{SymbolTable, Token};
+use super::{float_constant_name, Location, Opcode, SymbolTable, SymbolType, Token};
+use crate::instruction::{OperandKind, INSTRUCTION_LENGTH};
 use nom::types::CompleteStr;
 
-const MAX_I16: i32 = 32768;
-const MIN_I16: i32 = -32768;
-
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
@@ -16,28 +15,146 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// Where the instruction starts in the source, filled in by the program parser
+    pub location: Location,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut results = vec![];
-        match self.opcode {
-            Some(Token::Op { code }) => {
-                results.push(code as u8);
+        let code = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return Err(AssemblerError::NonOpcodeInOpcodeField),
+        };
+        let code = self.alternate_form(code);
+        self.check_operands(code)?;
+        if let Some((register, token)) = self.wide_load() {
+            let value = match token {
+                Token::LabelUsage { name } => match symbols.symbol_value(name) {
+                    Some(value) => value as i64,
+                    None => return Err(AssemblerError::UndefinedLabel { name: name.clone() }),
+                },
+                Token::IntegerOperand { value } => *value,
+                _ => return Err(AssemblerError::IncorrectOperands { opcode: code }),
+            };
+            return AssemblerInstruction::expand_wide_load(register, value);
+        }
+        results.push(u8::from(code));
+
+        for (token, kind) in self.operands().into_iter().zip(code.operands()) {
+            match self.float_literal(token) {
+                Some(value) => {
+                    let token = Token::FloatOperand { value };
+                    AssemblerInstruction::extract_operand(&token, *kind, symbols, &mut results)?;
+                }
+                None => AssemblerInstruction::extract_operand(token, *kind, symbols, &mut results)?,
             }
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+        }
+
+        while results.len() < INSTRUCTION_LENGTH {
+            results.push(0);
+        }
+
+        Ok(results)
+    }
+
+    /// Number of bytes this line occupies in the assembled program
+    pub fn encoded_len(&self) -> usize {
+        if self.wide_load().is_some() {
+            2 * INSTRUCTION_LENGTH
+        } else if self.is_opcode() {
+            INSTRUCTION_LENGTH
+        } else {
+            0
+        }
+    }
+
+    /// The register and operand of a `load` that needs more than LOAD's 16-bit immediate:
+    /// one of a literal that does not fit, or of any label. Label values are not known
+    /// until every instruction's length has been decided, so labels are always widened.
+    fn wide_load(&self) -> Option<(u8, &Token)> {
+        match (&self.opcode, &self.operand1, &self.operand2, &self.operand3) {
+            (
+                Some(Token::Op { code: Opcode::LOAD }),
+                Some(Token::Register { reg_num }),
+                Some(token),
+                None,
+            ) => match token {
+                Token::IntegerOperand { value } => {
+                    let (min, max) = OperandKind::Immediate.range();
+                    if *value < min || *value > max {
+                        Some((*reg_num, token))
+                    } else {
+                        None
+                    }
+                }
+                Token::LabelUsage { .. } => Some((*reg_num, token)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Encodes a wide `load` as a LOAD of the lower 16 bits followed by a LUI of the upper
+    /// 16 bits. Values may be written signed or unsigned, e.g. `#-1` or `#0xFFFFFFFF`.
+    fn expand_wide_load(register: u8, value: i64) -> Result<Vec<u8>, AssemblerError> {
+        let (min, max) = (i32::MIN as i64, u32::MAX as i64);
+        if value < min || value > max {
+            return Err(AssemblerError::IntegerOutOfRange { value, min, max });
+        }
+        let [b0, b1, b2, b3] = (value as u32).to_be_bytes();
+        Ok(vec![
+            u8::from(Opcode::LOAD),
+            register,
+            b2,
+            b3,
+            u8::from(Opcode::LUI),
+            register,
+            b0,
+            b1,
+        ])
+    }
+
+    /// The float `t` stands for, if it is a float literal. `loadf64` reads integer literals
+    /// as floats too, as `.f64` does, so `#2` is 2.0 rather than an offset into the data;
+    /// offsets can still be given through a label.
+    pub fn float_literal(&self, t: &Token) -> Option<f64> {
+        match t {
+            Token::FloatOperand { value } => Some(*value),
+            Token::IntegerOperand { value }
+                if self.opcode
+                    == Some(Token::Op {
+                        code: Opcode::LOADF64,
+                    }) =>
+            {
+                Some(*value as f64)
             }
-        };
+            _ => None,
+        }
+    }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results)
+    /// The opcode to encode an instruction such as `add $0 #1` with: the alternate form
+    /// of `code` when the operands fit that rather than `code`, otherwise `code` itself
+    fn alternate_form(&self, code: Opcode) -> Opcode {
+        match code.alternate_form() {
+            Some(alternate)
+                if self.check_operands(code).is_err() && self.check_operands(alternate).is_ok() =>
+            {
+                alternate
             }
+            _ => code,
         }
+    }
 
-        results
+    /// Whether the instruction is a SYSCALL or CALLHOST, whose string operands name host
+    /// functions
+    pub fn calls_host(&self) -> bool {
+        matches!(
+            self.opcode,
+            Some(Token::Op {
+                code: Opcode::SYSCALL | Opcode::CALLHOST
+            })
+        )
     }
 
     pub fn is_label(&self) -> bool {
@@ -52,33 +169,130 @@ impl AssemblerInstruction {
         self.directive.is_some()
     }
 
+    /// The operands that are present, in order
+    pub fn operands(&self) -> Vec<&Token> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Checks the operands against the ones `code` takes
+    fn check_operands(&self, code: Opcode) -> Result<(), AssemblerError> {
+        let operands = self.operands();
+        let expected = code.operands();
+        let matches = operands.len() == expected.len()
+            && operands
+                .iter()
+                .zip(expected)
+                .all(|(token, kind)| AssemblerInstruction::accepts(token, *kind));
+        if !matches {
+            return Err(AssemblerError::IncorrectOperands { opcode: code });
+        }
+        Ok(())
+    }
+
+    /// Whether `t` can be written where an operand of `kind` is expected
+    fn accepts(t: &Token, kind: OperandKind) -> bool {
+        match t {
+            Token::Register { .. } => kind == OperandKind::Register,
+            Token::FloatRegister { .. } => kind == OperandKind::FloatRegister,
+            Token::IntegerOperand { .. } | Token::LabelUsage { .. } => {
+                kind == OperandKind::Immediate
+                    || kind == OperandKind::Address
+                    || kind == OperandKind::Constant
+                    || kind == OperandKind::Offset
+                    || kind == OperandKind::HostFunction
+            }
+            Token::IrString { .. } => kind == OperandKind::Import,
+            Token::FloatOperand { .. } => kind == OperandKind::Constant,
+            _ => false,
+        }
+    }
+
     pub fn get_label_name(&self) -> Option<String> {
         match &self.label {
-            Some(l) => match l {
-                Token::LabelDeclaration { name } => Some(name.clone()),
-                _ => None,
-            },
-            None => None,
+            Some(Token::LabelDeclaration { name }) => Some(name.clone()),
+            _ => None,
         }
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
+    fn extract_operand(
+        t: &Token,
+        kind: OperandKind,
+        symbols: &SymbolTable,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
         match t {
-            Token::Register { reg_num } => {
+            Token::Register { reg_num } | Token::FloatRegister { reg_num } => {
                 results.push(*reg_num);
             }
+            // Float literals were pooled in the read-only data during the first phase
+            Token::FloatOperand { value } => {
+                match symbols.symbol_value(&float_constant_name(*value)) {
+                    Some(offset) => {
+                        AssemblerInstruction::extract_integer(offset as i64, kind, results)?;
+                    }
+                    None => {
+                        return Err(AssemblerError::InvalidOperand {
+                            token: format!("#{:?} outside of an assembled program", value),
+                        });
+                    }
+                }
+            }
             Token::IntegerOperand { value } => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
+                AssemblerInstruction::extract_integer(*value, kind, results)?;
             }
+            // Host function names were added to the imports during the first phase
+            Token::IrString { name } => match symbols.import(name) {
+                Some(index) => {
+                    AssemblerInstruction::extract_integer(index as i64, kind, results)?;
+                }
+                None => {
+                    return Err(AssemblerError::InvalidOperand {
+                        token: format!("'{}' outside of an assembled program", name),
+                    });
+                }
+            },
+            Token::LabelUsage { name } => match symbols.symbol(name) {
+                Some(symbol) => {
+                    // Code labels are absolute addresses and data labels are offsets into
+                    // the read-only data, so one cannot stand in for the other
+                    let is_code = *symbol.symbol_type() == SymbolType::Label;
+                    if kind == OperandKind::Address && !is_code {
+                        return Err(AssemblerError::NotACodeLabel { name: name.clone() });
+                    }
+                    if kind == OperandKind::Constant && is_code {
+                        return Err(AssemblerError::NotADataLabel { name: name.clone() });
+                    }
+                    AssemblerInstruction::extract_integer(symbol.offset() as i64, kind, results)?;
+                }
+                None => {
+                    return Err(AssemblerError::UndefinedLabel { name: name.clone() });
+                }
+            },
             _ => {
-                println!("Opcode found in operand field");
-                std::process::exit(1);
+                return Err(AssemblerError::InvalidOperand {
+                    token: format!("{:?}", t),
+                });
             }
         }
+        Ok(())
+    }
+
+    /// Encodes `value` big-endian in as many bytes as `kind` takes, if it fits
+    fn extract_integer(
+        value: i64,
+        kind: OperandKind,
+        results: &mut Vec<u8>,
+    ) -> Result<(), AssemblerError> {
+        let (min, max) = kind.range();
+        if value < min || value > max {
+            return Err(AssemblerError::IntegerOutOfRange { value, min, max });
+        }
+        let bytes = value.to_be_bytes();
+        results.extend_from_slice(&bytes[bytes.len() - kind.encoded_len()..]);
+        Ok(())
     }
 }
 
@@ -97,21 +311,24 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
 );
 
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        l: opt!(label_declaration) >>
-        o: opcode >>
-        o1: opt!(operand) >>
-        o2: opt!(operand) >>
-        o3: opt!(operand) >>
-        (
-            AssemblerInstruction{
-                opcode: Some(o),
-                label: l,
-                directive: None,
-                operand1: o1,
-                operand2: o2,
-                operand3: o3,
-            }
+    ws!(
+        do_parse!(
+            l: opt!(label_declaration) >>
+            o: opcode >>
+            o1: opt!(operand) >>
+            o2: opt!(operand) >>
+            o3: opt!(operand) >>
+            (
+                AssemblerInstruction{
+                    opcode: Some(o),
+                    label: l,
+                    directive: None,
+                    operand1: o1,
+                    operand2: o2,
+                    operand3: o3,
+                    location: Location::default(),
+                }
+            )
         )
     )
 );
@@ -119,7 +336,6 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_instruction_form_one() {
@@ -134,7 +350,8 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::IntegerOperand { value: 100 }),
-                    operand3: None
+                    operand3: None,
+                    location: Location::default(),
                 }
             ))
         );
@@ -153,7 +370,8 @@ mod tests {
                     directive: None,
                     operand1: None,
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    location: Location::default(),
                 }
             ))
         );
@@ -173,8 +391,41 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: Some(Token::Register { reg_num: 2 }),
+                    location: Location::default(),
                 }
             ))
         );
     }
+
+    #[test]
+    fn test_alternate_forms() {
+        let symbols = SymbolTable::new();
+        let bytes = |source: &str| {
+            let (_, i) = instruction(CompleteStr(source)).unwrap();
+            i.to_bytes(&symbols)
+        };
+        assert_eq!(bytes("add $0 #-3"), Ok(vec![58, 0, 0xFF, 0xFD]));
+        assert_eq!(bytes("lte $1 #7"), Ok(vec![67, 1, 0, 7]));
+        assert_eq!(bytes("add $0 $1 $2"), Ok(vec![1, 0, 1, 2]));
+        assert_eq!(
+            bytes("add $0 $1 #2"),
+            Err(AssemblerError::IncorrectOperands {
+                opcode: Opcode::ADD
+            })
+        );
+        assert_eq!(
+            AssemblerError::IncorrectOperands {
+                opcode: Opcode::ADD
+            }
+            .to_string(),
+            "add expects operands: register, register, register or register, immediate"
+        );
+        assert_eq!(
+            AssemblerError::IncorrectOperands {
+                opcode: Opcode::MOV
+            }
+            .to_string(),
+            "mov expects operands: register, register"
+        );
+    }
 }
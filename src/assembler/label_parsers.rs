@@ -1,13 +1,18 @@
+use nom::multispace;
 use nom::types::CompleteStr;
-use nom::{alphanumeric, multispace};
 
 use super::Token;
 
+// Label names are letters, digits and underscores, such as `L_0040`
+named!(label_name<CompleteStr, CompleteStr>,
+    take_while1!(|c: char| c.is_alphanumeric() || c == '_')
+);
+
 // Looks for a user-defined label, such as `label1:`
 named!(pub label_declaration<CompleteStr, Token>,
     ws!(
         do_parse!(
-            name: alphanumeric >>
+            name: label_name >>
             tag!(":") >>
             opt!(multispace) >>
             (
@@ -22,7 +27,7 @@ named!(pub label_usage<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("@") >>
-            name: alphanumeric >>
+            name: label_name >>
             opt!(multispace) >>
             (
                 Token::LabelUsage{name: name.to_string()}
@@ -49,6 +54,13 @@ mod tests {
         );
         let result = label_declaration(CompleteStr("test"));
         assert_eq!(result.is_ok(), false);
+        let (_, token) = label_declaration(CompleteStr("L_0040:")).unwrap();
+        assert_eq!(
+            token,
+            Token::LabelDeclaration {
+                name: "L_0040".to_string()
+            }
+        );
     }
 
     #[test]
@@ -1,33 +1,71 @@
+pub mod assembler_errors;
 pub mod directive_parsers;
 pub mod instruction_parsers;
 pub mod label_parsers;
 pub mod opcode_parsers;
 pub mod operand_parsers;
+pub mod pie_header;
 pub mod program_parsers;
 pub mod register_parsers;
-use nom::types::CompleteStr;
+use std::fmt;
 
 pub use crate::instruction::Opcode;
 
-use self::program_parsers::{program, Program};
+use self::assembler_errors::{AssembleError, AssemblerError};
+use self::instruction_parsers::AssemblerInstruction;
+use self::pie_header::PieHeader;
+use self::program_parsers::{parse_program, Program};
 
 pub(crate) const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub(crate) const PIE_HEADER_LENGTH: usize = 64;
+/// Version of the PIE layout written by the assembler and accepted by the VM
+pub(crate) const PIE_VERSION: u16 = 1;
+/// Number of addresses a two-byte address operand can reach. The header, the read-only
+/// data and the code all have to fit in it.
+pub(crate) const ADDRESS_SPACE: usize = u16::MAX as usize + 1;
+/// Most read-only data a program can have, which leaves no room for code at all
+pub(crate) const MAX_DATA_LENGTH: usize = ADDRESS_SPACE - PIE_HEADER_LENGTH;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
-    IntegerOperand { value: i32 },
+    FloatRegister { reg_num: u8 },
+    IntegerOperand { value: i64 },
+    FloatOperand { value: f64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    IrString { name: String },
 }
 
-#[derive(Debug)]
+/// Position of an instruction in the source, both 1-based
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbol_table: SymbolTable,
+    /// Read-only data collected from the `.data` section
+    pub ro: Vec<u8>,
+    /// Section the instructions being read belong to
+    current_section: AssemblerSection,
+    /// Label given to `.entry`, where the VM starts executing
+    entry_label: Option<(String, Location)>,
+    /// Leaves the symbol section out of the assembled program
+    pub strip_symbols: bool,
+    /// Name of the file being assembled, used in error messages
+    pub file_name: Option<String>,
 }
 
 impl Assembler {
@@ -35,83 +73,332 @@ impl Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbol_table: SymbolTable::new(),
+            ro: vec![],
+            current_section: AssemblerSection::Code,
+            entry_label: None,
+            strip_symbols: false,
+            file_name: None,
         }
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program(CompleteStr(raw)) {
-            Ok((_remainder, program)) => {
-                // First get the header so we can smush it into the bytecode letter
-                let mut assembled_program = self.write_pie_header();
-                self.process_first_phase(&program);
-                let mut body = self.process_second_phase(&program);
+    /// Assembles `raw` into a PIE program, or returns every error found in it, in source
+    /// order
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssembleError>> {
+        let (program, mut errors) = parse_program(raw);
+        let mut failures = vec![];
+        self.process_first_phase(&program, &mut failures);
+        let mut body = self.process_second_phase(&program, &mut failures);
+        // Stripped programs keep their imports, which the VM needs to run them
+        let mut symbols = if self.strip_symbols {
+            self.symbol_table.imports().to_bytes()
+        } else {
+            self.symbol_table.to_bytes()
+        };
 
-                // Merge the header with the populated body vector
-                assembled_program.append(&mut body);
-                Some(assembled_program)
+        // First get the header so we can smush it into the bytecode letter, then
+        // the read-only data, the code, and finally the symbols
+        let header = self.write_pie_header(body.len(), symbols.len());
+        let mut assembled_program = match header {
+            Ok(header) => header,
+            Err(failure) => {
+                failures.push(failure);
+                vec![]
             }
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                None
+        };
+
+        for (location, error) in failures {
+            errors.push(AssembleError::new(error, location, raw));
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|e| (e.location.line, e.location.column));
+            if let Some(file_name) = &self.file_name {
+                for e in &mut errors {
+                    e.file = file_name.clone();
+                }
             }
+            return Err(errors);
         }
+        assembled_program.extend_from_slice(&self.ro);
+        assembled_program.append(&mut body);
+        assembled_program.append(&mut symbols);
+        Ok(assembled_program)
     }
 
-    fn process_first_phase(&mut self, p: &Program) {
-        self.extract_labels(p);
+    fn process_first_phase(&mut self, p: &Program, errors: &mut Vec<(Location, AssemblerError)>) {
+        self.symbol_table = SymbolTable::new();
+        self.ro = vec![];
+        self.current_section = AssemblerSection::Code;
+        self.entry_label = None;
+        self.phase = AssemblerPhase::First;
+        self.extract_labels(p, errors);
         self.phase = AssemblerPhase::Second;
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(
+        &mut self,
+        p: &Program,
+        errors: &mut Vec<(Location, AssemblerError)>,
+    ) -> Vec<u8> {
         let mut program = vec![];
-        for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbol_table);
-            program.append(&mut bytes);
+        for i in p.instructions.iter().filter(|i| i.is_opcode()) {
+            match i.to_bytes(&self.symbol_table) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => errors.push((i.location, e)),
+            }
         }
         program
     }
 
-    fn extract_labels(&mut self, p: &Program) {
+    /// Records the address of every label and lays out the `.data` section. Code labels are
+    /// absolute, i.e. they include the PIE header and the read-only data that precede the
+    /// code; data labels are offsets into the read-only data.
+    fn extract_labels(&mut self, p: &Program, errors: &mut Vec<(Location, AssemblerError)>) {
         let mut offset = 0;
+        // Where the read-only data grew, and to what length, to check it against the code
+        let mut data_growth = vec![];
         for i in &p.instructions {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new(name, SymbolType::Label, offset);
-                        self.symbol_table.add_symbol(symbol);
+            let data_length = self.ro.len();
+            if i.is_directive() {
+                if let Err(e) = self.process_directive(i) {
+                    errors.push((i.location, e));
+                }
+                if self.ro.len() > data_length {
+                    data_growth.push((i.location, self.ro.len()));
+                }
+                continue;
+            }
+            if self.current_section != AssemblerSection::Code {
+                errors.push((i.location, AssemblerError::InstructionInDataSection));
+                continue;
+            }
+            if let Some(name) = i.get_label_name() {
+                let symbol = Symbol::new(name, SymbolType::Label, offset);
+                if let Err(e) = self.declare_symbol(symbol) {
+                    errors.push((i.location, e));
+                }
+            }
+            for operand in i.operands() {
+                if let Some(value) = i.float_literal(operand) {
+                    self.pool_float_constant(value);
+                } else if let Token::IrString { name } = operand {
+                    if i.calls_host() {
+                        self.import(name);
+                    }
+                }
+            }
+            if self.ro.len() > data_length {
+                data_growth.push((i.location, self.ro.len()));
+            }
+            offset += i.encoded_len() as u32;
+        }
+
+        // Code labels have to stay addressable, so the data can only use what the code
+        // leaves of the address space
+        let max = ADDRESS_SPACE.saturating_sub(PIE_HEADER_LENGTH + offset as usize);
+        if let Some((location, length)) = data_growth.iter().find(|(_, length)| *length > max) {
+            errors.push((
+                *location,
+                AssemblerError::DataSectionTooLarge {
+                    length: *length as u64,
+                    max,
+                },
+            ));
+        }
+
+        // Only now do we know how much read-only data sits in front of the code
+        let code_start = (PIE_HEADER_LENGTH + self.ro.len()) as u32;
+        for symbol in &mut self.symbol_table.symbols {
+            if symbol.symbol_type == SymbolType::Label {
+                symbol.offset += code_start;
+            }
+        }
+    }
+
+    /// Places a float literal used by an instruction in the read-only data, once per
+    /// distinct value, so the instruction can refer to it by offset
+    fn pool_float_constant(&mut self, value: f64) {
+        let name = float_constant_name(value);
+        if self.symbol_table.symbol(&name).is_none() {
+            let symbol = Symbol::new(name, SymbolType::Float, self.ro.len() as u32);
+            self.symbol_table.add_symbol(symbol);
+            self.ro.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    /// Adds a host function called by name to the imports, once per distinct name, so
+    /// that instructions can refer to it by index
+    fn import(&mut self, name: &str) {
+        if self.symbol_table.import(name).is_none() {
+            let index = self.symbol_table.imports().symbols.len() as u32;
+            let symbol = Symbol::new(name.to_string(), SymbolType::Import, index);
+            self.symbol_table.add_symbol(symbol);
+        }
+    }
+
+    /// Adds a label to the symbol table unless one with the same name exists
+    fn declare_symbol(&mut self, symbol: Symbol) -> Result<(), AssemblerError> {
+        if self.symbol_table.symbol(&symbol.name).is_some() {
+            return Err(AssemblerError::DuplicateLabel { name: symbol.name });
+        }
+        self.symbol_table.add_symbol(symbol);
+        Ok(())
+    }
+
+    fn process_directive(&mut self, i: &AssemblerInstruction) -> Result<(), AssemblerError> {
+        let name = match &i.directive {
+            Some(Token::Directive { name }) => name.as_str(),
+            _ => return Err(AssemblerError::NonOpcodeInOpcodeField),
+        };
+        let symbol_type = match name {
+            "data" => {
+                self.current_section = AssemblerSection::Data;
+                return Ok(());
+            }
+            "code" => {
+                self.current_section = AssemblerSection::Code;
+                return Ok(());
+            }
+            "entry" => {
+                return match i.operands().as_slice() {
+                    [Token::LabelUsage { name }] => {
+                        self.entry_label = Some((name.clone(), i.location));
+                        Ok(())
                     }
-                    None => {}
+                    _ => Err(AssemblerError::IncorrectDirectiveOperands {
+                        name: name.to_string(),
+                    }),
                 };
             }
-            offset += 4;
+            "asciiz" => SymbolType::IrString,
+            "word" => SymbolType::Word,
+            "byte" => SymbolType::Byte,
+            "space" => SymbolType::Space,
+            "f64" => SymbolType::Float,
+            _ => {
+                return Err(AssemblerError::UnknownDirective {
+                    name: name.to_string(),
+                })
+            }
+        };
+        if self.current_section != AssemblerSection::Data {
+            return Err(AssemblerError::DirectiveOutsideDataSection {
+                name: name.to_string(),
+            });
+        }
+
+        // Words and bytes may be written signed or unsigned, e.g. `.byte #-1` or `.byte #255`
+        let in_range = |value: i64, bits: u32| {
+            let (min, max) = (-(1i64 << (bits - 1)), (1i64 << bits) - 1);
+            if value < min || value > max {
+                return Err(AssemblerError::IntegerOutOfRange { value, min, max });
+            }
+            Ok(value)
+        };
+        let bytes = match (&symbol_type, i.operands().as_slice()) {
+            (SymbolType::IrString, [Token::IrString { name }]) => {
+                let mut bytes = name.as_bytes().to_vec();
+                bytes.push(0);
+                Some(bytes)
+            }
+            (SymbolType::Word, [Token::IntegerOperand { value }]) => {
+                Some((in_range(*value, 32)? as u32).to_be_bytes().to_vec())
+            }
+            (SymbolType::Byte, [Token::IntegerOperand { value }]) => {
+                Some(vec![in_range(*value, 8)? as u8])
+            }
+            (SymbolType::Float, [Token::FloatOperand { value }]) => {
+                Some(value.to_be_bytes().to_vec())
+            }
+            (SymbolType::Float, [Token::IntegerOperand { value }]) => {
+                Some((*value as f64).to_be_bytes().to_vec())
+            }
+            (SymbolType::Space, [Token::IntegerOperand { value }]) => {
+                if *value < 0 {
+                    return Err(AssemblerError::IntegerOutOfRange {
+                        value: *value,
+                        min: 0,
+                        max: MAX_DATA_LENGTH as i64,
+                    });
+                }
+                // Checked before allocating, so a huge .space cannot exhaust memory
+                if *value as u64 > (MAX_DATA_LENGTH - self.ro.len()) as u64 {
+                    return Err(AssemblerError::DataSectionTooLarge {
+                        length: (self.ro.len() as u64).saturating_add(*value as u64),
+                        max: MAX_DATA_LENGTH,
+                    });
+                }
+                Some(vec![0; *value as usize])
+            }
+            _ => None,
+        };
+        let mut bytes = bytes.ok_or_else(|| AssemblerError::IncorrectDirectiveOperands {
+            name: name.to_string(),
+        })?;
+        let length = self.ro.len() + bytes.len();
+        if length > MAX_DATA_LENGTH {
+            return Err(AssemblerError::DataSectionTooLarge {
+                length: length as u64,
+                max: MAX_DATA_LENGTH,
+            });
+        }
+
+        if let Some(label) = i.get_label_name() {
+            let symbol = Symbol::new(label, symbol_type, self.ro.len() as u32);
+            self.declare_symbol(symbol)?;
         }
+        self.ro.append(&mut bytes);
+        Ok(())
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            header.push(byte.clone());
+    /// Describes where the read-only data, code and symbols will be laid out after the
+    /// header, and where execution starts
+    fn write_pie_header(
+        &self,
+        code_length: usize,
+        symbol_length: usize,
+    ) -> Result<Vec<u8>, (Location, AssemblerError)> {
+        let mut header = PieHeader::new(self.ro.len() as u32, code_length as u32);
+        if let Some((name, location)) = &self.entry_label {
+            header.entry_point = match self.symbol_table.symbol(name) {
+                Some(symbol) if symbol.symbol_type == SymbolType::Label => symbol.offset,
+                Some(_) => {
+                    let error = AssemblerError::IncorrectDirectiveOperands {
+                        name: "entry".to_string(),
+                    };
+                    return Err((*location, error));
+                }
+                None => {
+                    let error = AssemblerError::UndefinedLabel { name: name.clone() };
+                    return Err((*location, error));
+                }
+            };
         }
-        while header.len() <= PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+        if symbol_length > 0 {
+            header.symbol_offset = header.code_end() as u32;
+            header.symbol_length = symbol_length as u32;
         }
-        header
+        Ok(header.to_bytes())
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Sections an assembly file is divided into
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum AssemblerSection {
+    /// Read-only data, declared after `.data`
+    Data,
+    /// Instructions, declared after `.code`. Code is assumed until a section is declared.
+    #[default]
+    Code,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum AssemblerPhase {
+    #[default]
     First,
     Second,
 }
 
-impl Default for AssemblerPhase {
-    fn default() -> Self {
-        AssemblerPhase::First
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Symbol {
     name: String,
     offset: u32,
@@ -126,14 +413,76 @@ impl Symbol {
             symbol_type,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SymbolType {
+    /// Address of an instruction
     Label,
+    /// Offset of a NUL-terminated string declared with `.asciiz`
+    IrString,
+    /// Offset of a 32-bit integer declared with `.word`
+    Word,
+    /// Offset of a byte declared with `.byte`
+    Byte,
+    /// Offset of a zero-filled block declared with `.space`
+    Space,
+    /// Offset of a 64-bit float declared with `.f64`, or pooled from a float literal
+    Float,
+    /// Name of a host function the program calls, whose offset is its index in the
+    /// imports. Imports do not share a namespace with labels.
+    Import,
+}
+
+impl From<SymbolType> for u8 {
+    fn from(t: SymbolType) -> Self {
+        match t {
+            SymbolType::Label => 0,
+            SymbolType::IrString => 1,
+            SymbolType::Word => 2,
+            SymbolType::Byte => 3,
+            SymbolType::Space => 4,
+            SymbolType::Float => 5,
+            SymbolType::Import => 6,
+        }
+    }
+}
+
+impl TryFrom<u8> for SymbolType {
+    type Error = u8;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(SymbolType::Label),
+            1 => Ok(SymbolType::IrString),
+            2 => Ok(SymbolType::Word),
+            3 => Ok(SymbolType::Byte),
+            4 => Ok(SymbolType::Space),
+            5 => Ok(SymbolType::Float),
+            6 => Ok(SymbolType::Import),
+            _ => Err(v),
+        }
+    }
+}
+
+/// Name of the read-only data symbol a float literal is pooled under
+pub fn float_constant_name(value: f64) -> String {
+    format!("__f64_{:016x}", value.to_bits())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
@@ -148,12 +497,73 @@ impl SymbolTable {
     }
 
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
-        for symbol in &self.symbols {
-            if symbol.name == s {
-                return Some(symbol.offset);
+        self.symbol(s).map(|symbol| symbol.offset)
+    }
+
+    /// The label or data symbol called `s`
+    pub fn symbol(&self, s: &str) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s && symbol.symbol_type != SymbolType::Import)
+    }
+
+    /// Index of the host function called `name` in the imports
+    pub fn import(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name && symbol.symbol_type == SymbolType::Import)
+            .map(|symbol| symbol.offset)
+    }
+
+    /// A table of just the imports
+    pub fn imports(&self) -> SymbolTable {
+        SymbolTable {
+            symbols: self
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.symbol_type == SymbolType::Import)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Encodes the table for the PIE symbol section. Each symbol is written as its type,
+    /// its offset as a big-endian u32, and its name prefixed with a one-byte length.
+    /// Data symbols come first, then code labels, each ordered by offset, so that the
+    /// section does not depend on the order the source declared them in.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut symbols: Vec<&Symbol> = self.symbols.iter().collect();
+        symbols.sort_by_key(|s| (s.symbol_type == SymbolType::Label, s.offset));
+        let mut bytes = vec![];
+        for symbol in symbols {
+            let name = &symbol.name.as_bytes()[..symbol.name.len().min(u8::MAX as usize)];
+            bytes.push(u8::from(symbol.symbol_type));
+            bytes.extend_from_slice(&symbol.offset.to_be_bytes());
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name);
+        }
+        bytes
+    }
+
+    /// Decodes a PIE symbol section, returning `None` if it is malformed
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<SymbolTable> {
+        let mut table = SymbolTable::new();
+        while !bytes.is_empty() {
+            if bytes.len() < 6 {
+                return None;
             }
+            let symbol_type = SymbolType::try_from(bytes[0]).ok()?;
+            let offset = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+            let name_end = 6 + bytes[5] as usize;
+            let name = String::from_utf8(bytes.get(6..name_end)?.to_vec()).ok()?;
+            table.add_symbol(Symbol::new(name, symbol_type, offset));
+            bytes = &bytes[name_end..];
         }
-        None
+        Some(table)
     }
 }
 
@@ -161,6 +571,18 @@ impl SymbolTable {
 mod tests {
     use super::*;
 
+    fn code_section(program: &[u8]) -> &[u8] {
+        let header = PieHeader::parse(program).unwrap();
+        &program[header.code_offset as usize..header.code_end()]
+    }
+
+    /// Assembles `source`, expecting it to fail, and returns where and why
+    fn assemble_errors(source: &str) -> Vec<(Location, AssemblerError)> {
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(source).unwrap_err();
+        errors.into_iter().map(|e| (e.location, e.error)).collect()
+    }
+
     #[test]
     fn test_symbol_table() {
         let mut sym = SymbolTable::new();
@@ -177,4 +599,428 @@ mod tests {
         let v = sym.symbol_value("symbol_which_does_not_exist");
         assert_eq!(v.is_some(), false);
     }
+
+    #[test]
+    fn test_assemble_call_label() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("call @sub\nhlt\nsub: ret\n").unwrap();
+        assert_eq!(asm.symbol_table.symbol_value("sub"), Some(72));
+        assert_eq!(
+            code_section(&program),
+            [22, 0, 72, 0, 5, 0, 0, 0, 23, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_label_operand() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $1 #1\nloop: load $0 @loop\n").unwrap();
+        assert_eq!(code_section(&program).len(), 12);
+        assert_eq!(code_section(&program)[4..], [0, 0, 0, 68, 24, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_extract_labels_mixed_lengths() {
+        let mut asm = Assembler::new();
+        asm.assemble("hlt\none: jmp $0\ntwo: load $0 #1\n.code\nthree: add $0 $1 $2\n")
+            .unwrap();
+        assert_eq!(asm.symbol_table.symbol_value("one"), Some(68));
+        assert_eq!(asm.symbol_table.symbol_value("two"), Some(72));
+        assert_eq!(asm.symbol_table.symbol_value("three"), Some(76));
+    }
+
+    #[test]
+    fn test_assemble_too_many_operands() {
+        assert_eq!(
+            assemble_errors("load #1 #2 #3\n"),
+            vec![(
+                Location { line: 1, column: 1 },
+                AssemblerError::IncorrectOperands {
+                    opcode: Opcode::LOAD
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_assemble_operand_kind_mismatch() {
+        assert_eq!(
+            assemble_errors("load $0 #1\n  add #5\nhlt $1\n"),
+            vec![
+                (
+                    Location { line: 2, column: 3 },
+                    AssemblerError::IncorrectOperands {
+                        opcode: Opcode::ADD
+                    }
+                ),
+                (
+                    Location { line: 3, column: 1 },
+                    AssemblerError::IncorrectOperands {
+                        opcode: Opcode::HLT
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_undefined_label() {
+        assert_eq!(
+            assemble_errors("load $0 @nowhere\n"),
+            vec![(
+                Location { line: 1, column: 1 },
+                AssemblerError::UndefinedLabel {
+                    name: "nowhere".to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_assemble_label_kinds() {
+        assert_eq!(
+            assemble_errors(
+                ".data\nmsg: .asciiz 'Hi'\n.code\nstart: call @msg\nprts @start\nload $0 @msg\n"
+            ),
+            vec![
+                (
+                    Location { line: 4, column: 1 },
+                    AssemblerError::NotACodeLabel {
+                        name: "msg".to_string()
+                    }
+                ),
+                (
+                    Location { line: 5, column: 1 },
+                    AssemblerError::NotADataLabel {
+                        name: "start".to_string()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_data_section() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\nhello: .asciiz 'Hello'\nnum: .word #258\nb: .byte #7\nbuf: .space #2\n\
+                 .code\nstart: load $0 @hello\nload $1 @num\n",
+            )
+            .unwrap();
+        assert_eq!(asm.ro, vec![72, 101, 108, 108, 111, 0, 0, 0, 1, 2, 7, 0, 0]);
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.data_length, 13);
+        assert_eq!(header.data(&program), &asm.ro[..]);
+        assert_eq!(asm.symbol_table.symbol_value("hello"), Some(0));
+        assert_eq!(asm.symbol_table.symbol_value("num"), Some(6));
+        assert_eq!(asm.symbol_table.symbol_value("b"), Some(10));
+        assert_eq!(asm.symbol_table.symbol_value("buf"), Some(11));
+        assert_eq!(
+            asm.symbol_table.symbol_value("start"),
+            Some(PIE_HEADER_LENGTH as u32 + 13)
+        );
+        assert_eq!(
+            code_section(&program),
+            [0, 0, 0, 0, 24, 0, 0, 0, 0, 1, 0, 6, 24, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_data_limit() {
+        assert_eq!(
+            assemble_errors(".data\nbuf: .space #0x7FFFFFFFFFFFFFFF\n"),
+            vec![(
+                Location { line: 2, column: 1 },
+                AssemblerError::DataSectionTooLarge {
+                    length: i64::MAX as u64,
+                    max: 65472
+                }
+            )]
+        );
+        assert_eq!(
+            assemble_errors(".data\n.space #65464\n.space #-1\n.word #1\n.word #2\n.code\nhlt\n"),
+            vec![
+                (
+                    Location { line: 3, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: -1,
+                        min: 0,
+                        max: 65472
+                    }
+                ),
+                (
+                    Location { line: 5, column: 1 },
+                    AssemblerError::DataSectionTooLarge {
+                        length: 65472,
+                        max: 65468
+                    }
+                ),
+            ]
+        );
+        // The code after the data has to stay within reach of `call`
+        assert_eq!(
+            assemble_errors(".data\n.space #65470\n.code\ncall @sub\nsub: ret\n"),
+            vec![
+                (
+                    Location { line: 2, column: 1 },
+                    AssemblerError::DataSectionTooLarge {
+                        length: 65470,
+                        max: 65464
+                    }
+                ),
+                (
+                    Location { line: 4, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: 65538,
+                        min: 0,
+                        max: 65535
+                    }
+                ),
+            ]
+        );
+        let mut asm = Assembler::new();
+        asm.assemble(
+            ".data\n.space #65452\nlast: .f64 #1.5\n.code\nloadf64 $f0 @last\ncall @end\n\
+             end: hlt\n",
+        )
+        .unwrap();
+        assert_eq!(asm.symbol_table.symbol_value("end"), Some(65532));
+    }
+
+    #[test]
+    fn test_assemble_section_errors() {
+        assert_eq!(
+            assemble_errors(".asciiz 'Hi'\n.data\nhlt\n.bogus\n.byte #300\n"),
+            vec![
+                (
+                    Location { line: 1, column: 1 },
+                    AssemblerError::DirectiveOutsideDataSection {
+                        name: "asciiz".to_string()
+                    }
+                ),
+                (
+                    Location { line: 3, column: 1 },
+                    AssemblerError::InstructionInDataSection
+                ),
+                (
+                    Location { line: 4, column: 1 },
+                    AssemblerError::UnknownDirective {
+                        name: "bogus".to_string()
+                    }
+                ),
+                (
+                    Location { line: 5, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: 300,
+                        min: -128,
+                        max: 255
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_header() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\nn: .word #1\n.code\nhlt\nstart: hlt\n.entry @start\n")
+            .unwrap();
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.version, PIE_VERSION);
+        assert_eq!(header.data_offset, PIE_HEADER_LENGTH as u32);
+        assert_eq!(header.data_length, 4);
+        assert_eq!(header.code_offset, PIE_HEADER_LENGTH as u32 + 4);
+        assert_eq!(header.code_length, 8);
+        assert_eq!(header.entry_point, PIE_HEADER_LENGTH as u32 + 8);
+        assert_eq!(header.symbol_offset as usize, header.code_end());
+
+        let symbols = SymbolTable::from_bytes(header.symbols(&program).unwrap()).unwrap();
+        assert_eq!(symbols.symbol_value("n"), Some(0));
+        assert_eq!(
+            *symbols.symbol("n").unwrap().symbol_type(),
+            SymbolType::Word
+        );
+        assert_eq!(symbols.symbol_value("start"), Some(header.entry_point));
+    }
+
+    #[test]
+    fn test_assemble_undefined_entry() {
+        assert_eq!(
+            assemble_errors("hlt\n.entry @main\n"),
+            vec![(
+                Location { line: 2, column: 1 },
+                AssemblerError::UndefinedLabel {
+                    name: "main".to_string()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_assemble_collects_errors() {
+        let mut asm = Assembler::new();
+        asm.file_name = Some("test.iasm".to_string());
+        let errors = asm
+            .assemble("loop: hlt\nloop: lod $0\ncall @missing\nload $0 #1 #2 #3\n")
+            .unwrap_err();
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "test.iasm:2:1: error: label `loop` is already declared\n  |\n\
+                 2 | loop: lod $0\n  | ^",
+                "test.iasm:2:7: error: unknown mnemonic `lod`\n  |\n\
+                 2 | loop: lod $0\n  |       ^",
+                "test.iasm:3:1: error: undefined label: @missing\n  |\n\
+                 3 | call @missing\n  | ^",
+                "test.iasm:4:15: error: unexpected `#3`\n  |\n\
+                 4 | load $0 #1 #2 #3\n  |               ^",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_integer_literals() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\nw: .word #-1\nb: .byte #0xFF\n.byte #-128\n\
+                 .code\nload $0 #-1\nload $1 #0x7FFF\nload $2 #'A'\nload $3 #-0b1000\n",
+            )
+            .unwrap();
+        assert_eq!(asm.ro, vec![255, 255, 255, 255, 255, 128]);
+        assert_eq!(
+            code_section(&program),
+            [0, 0, 255, 255, 0, 1, 127, 255, 0, 2, 0, 65, 0, 3, 255, 248]
+        );
+    }
+
+    #[test]
+    fn test_assemble_wide_load() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #100000\nload $1 #-100000\nnext: load $2 #32767\n")
+            .unwrap();
+        assert_eq!(
+            code_section(&program),
+            [
+                0, 0, 0x86, 0xA0, 24, 0, 0x00, 0x01, // load $0 #100000
+                0, 1, 0x79, 0x60, 24, 1, 0xFF, 0xFE, // load $1 #-100000
+                0, 2, 0x7F, 0xFF, // load $2 #32767
+            ]
+        );
+        assert_eq!(asm.symbol_table.symbol_value("next"), Some(80));
+    }
+
+    #[test]
+    fn test_assemble_wide_load_label() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\nbuf: .space #40000\nmsg: .asciiz 'Hi'\n.code\nload $0 @msg\n")
+            .unwrap();
+        assert_eq!(code_section(&program), [0, 0, 0x9C, 0x40, 24, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_integer_out_of_range() {
+        assert_eq!(
+            assemble_errors("load $0 #0x100000000\nload $1 #-2147483649\ncall #65536\ncall #-1\n"),
+            vec![
+                (
+                    Location { line: 1, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: 0x1_0000_0000,
+                        min: -2147483648,
+                        max: 4294967295
+                    }
+                ),
+                (
+                    Location { line: 2, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: -2147483649,
+                        min: -2147483648,
+                        max: 4294967295
+                    }
+                ),
+                (
+                    Location { line: 3, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: 65536,
+                        min: 0,
+                        max: 65535
+                    }
+                ),
+                (
+                    Location { line: 4, column: 1 },
+                    AssemblerError::IntegerOutOfRange {
+                        value: -1,
+                        min: 0,
+                        max: 65535
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_float_constants() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\nhalf: .f64 #0.5\none: .f64 #1\n.code\nloadf64 $f0 #2.5\n\
+                 loadf64 $f1 @half\nloadf64 $f2 #2.5\n",
+            )
+            .unwrap();
+        let mut ro = vec![];
+        for value in &[0.5f64, 1.0, 2.5] {
+            ro.extend_from_slice(&value.to_be_bytes());
+        }
+        assert_eq!(asm.ro, ro);
+        assert_eq!(
+            asm.symbol_table.symbol_value(&float_constant_name(2.5)),
+            Some(16)
+        );
+        assert_eq!(
+            code_section(&program),
+            [25, 0, 0, 16, 25, 1, 0, 0, 25, 2, 0, 16]
+        );
+    }
+
+    #[test]
+    fn test_assemble_float_integer_literal() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("loadf64 $f0 #2\nloadf64 $f1 #2.0\n").unwrap();
+        assert_eq!(asm.ro, 2.0f64.to_be_bytes());
+        assert_eq!(code_section(&program), [25, 0, 0, 0, 25, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_strip_symbols() {
+        let mut asm = Assembler::new();
+        asm.strip_symbols = true;
+        let program = asm.assemble("start: hlt\n").unwrap();
+        let header = PieHeader::parse(&program).unwrap();
+        assert_eq!(header.symbols(&program), None);
+        assert_eq!(program.len(), header.code_end());
+    }
+
+    #[test]
+    fn test_assemble_imports() {
+        let mut asm = Assembler::new();
+        asm.strip_symbols = true;
+        let program = asm
+            .assemble("start: syscall 'print'\nsyscall 'read'\nsyscall 'print'\nsyscall #2\n")
+            .unwrap();
+        assert_eq!(
+            code_section(&program),
+            [69, 0, 0, 0, 69, 0, 1, 0, 69, 0, 0, 0, 68, 0, 2, 0]
+        );
+        let header = PieHeader::parse(&program).unwrap();
+        let symbols = SymbolTable::from_bytes(header.symbols(&program).unwrap()).unwrap();
+        assert_eq!(symbols.import("print"), Some(0));
+        assert_eq!(symbols.import("read"), Some(1));
+        assert_eq!(symbols.symbol_value("start"), None);
+    }
 }
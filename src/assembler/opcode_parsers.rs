@@ -5,7 +5,7 @@ use nom::*;
 
 named!(pub opcode<CompleteStr, Token>,
   do_parse!(
-      opcode: alpha1 >>
+      opcode: alphanumeric1 >>
       (
         {
             Token::Op{code: Opcode::from(opcode)}
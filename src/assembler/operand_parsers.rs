@@ -1,17 +1,99 @@
+use super::label_parsers::label_usage;
 use super::register_parsers::register;
 use super::Token;
-use nom::digit;
 use nom::types::CompleteStr;
+use nom::{anychar, digit, hex_digit, oct_digit};
+
+// Parser for floating point numbers, which have a fractional part or an exponent:
+// #3.14, #-0.5 or #1e-7
+named!(pub float_operand<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("#") >>
+            value: map_res!(
+                recognize!(tuple!(
+                    opt!(char!('-')),
+                    digit,
+                    alt!(
+                        recognize!(pair!(preceded!(char!('.'), digit), opt!(exponent))) |
+                        exponent
+                    )
+                )),
+                |f: CompleteStr| f.parse::<f64>()
+            ) >>
+            (
+                Token::FloatOperand{value}
+            )
+        )
+    )
+);
+
+named!(exponent<CompleteStr, CompleteStr>,
+    recognize!(tuple!(one_of!("eE"), opt!(one_of!("+-")), digit))
+);
 
 // Parser for integer numbers, which we preface with `#` in our assembly language:
-// #100
+// #100, #-1, #0xFF, #0b1010, #0o17 or #'A'
 named!(pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            value: alt!(character_literal | signed_integer) >>
+            (
+                Token::IntegerOperand{value}
+            )
+        )
+    )
+);
+
+// A single character in quotes, such as 'A', whose value is its code point
+named!(character_literal<CompleteStr, i64>,
+    map!(
+        delimited!(char!('\''), anychar, char!('\'')),
+        |c: char| c as i64
+    )
+);
+
+named!(signed_integer<CompleteStr, i64>,
+    map_opt!(
+        pair!(opt!(char!('-')), unsigned_integer),
+        |(sign, magnitude): (Option<char>, u64)| {
+            let magnitude = i64::try_from(magnitude).ok()?;
+            Some(if sign.is_some() { -magnitude } else { magnitude })
+        }
+    )
+);
+
+// Decimal, or hexadecimal, binary and octal with a `0x`, `0b` or `0o` prefix
+named!(unsigned_integer<CompleteStr, u64>,
+    alt!(
+        preceded!(
+            tag_no_case!("0x"),
+            map_res!(hex_digit, |d: CompleteStr| u64::from_str_radix(&d, 16))
+        ) |
+        preceded!(
+            tag_no_case!("0b"),
+            map_res!(
+                take_while1!(|c: char| c == '0' || c == '1'),
+                |d: CompleteStr| u64::from_str_radix(&d, 2)
+            )
+        ) |
+        preceded!(
+            tag_no_case!("0o"),
+            map_res!(oct_digit, |d: CompleteStr| u64::from_str_radix(&d, 8))
+        ) |
+        map_res!(digit, |d: CompleteStr| d.parse::<u64>())
+    )
+);
+
+// Parser for string literals, which we wrap in single quotes in our assembly language:
+// 'Hello'
+named!(pub irstring<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            content: delimited!(tag!("'"), take_until!("'"), tag!("'")) >>
             (
-                Token::IntegerOperand{value: reg_num.parse::<i32>().unwrap()}
+                Token::IrString{name: content.to_string()}
             )
         )
     )
@@ -19,8 +101,11 @@ named!(pub integer_operand<CompleteStr, Token>,
 
 named!(pub operand<CompleteStr, Token>,
     alt!(
+        float_operand |
         integer_operand |
-        register
+        label_usage |
+        register |
+        irstring
     )
 );
 
@@ -42,4 +127,82 @@ mod tests {
         let result = integer_operand(CompleteStr("10"));
         assert_eq!(result.is_ok(), false);
     }
+
+    #[test]
+    fn test_parse_integer_literal_forms() {
+        for (literal, expected) in &[
+            ("#-1", -1),
+            ("#0xFF", 255),
+            ("#0b1010", 10),
+            ("#0o17", 15),
+            ("#'A'", 65),
+            ("#-0x10", -16),
+            ("#0", 0),
+            ("#4294967295", 4_294_967_295),
+        ] {
+            assert_eq!(
+                integer_operand(CompleteStr(literal)),
+                Ok((CompleteStr(""), Token::IntegerOperand { value: *expected })),
+                "{}",
+                literal
+            );
+        }
+        assert!(integer_operand(CompleteStr("#0x")).unwrap().0 != CompleteStr(""));
+        assert!(integer_operand(CompleteStr("#-")).is_err());
+        assert!(integer_operand(CompleteStr("#99999999999999999999")).is_err());
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        for (literal, expected) in &[
+            ("#2.75", 2.75),
+            ("#-0.5", -0.5),
+            ("#1e-7", 1e-7),
+            ("#2.5E3", 2500.0),
+        ] {
+            assert_eq!(
+                operand(CompleteStr(literal)),
+                Ok((CompleteStr(""), Token::FloatOperand { value: *expected })),
+                "{}",
+                literal
+            );
+        }
+        assert_eq!(
+            operand(CompleteStr("#100")),
+            Ok((CompleteStr(""), Token::IntegerOperand { value: 100 }))
+        );
+        assert!(float_operand(CompleteStr("#1.")).is_err());
+    }
+
+    #[test]
+    fn test_parse_string_operand() {
+        let result = irstring(CompleteStr("'This is a test'"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::IrString {
+                    name: "This is a test".to_string()
+                }
+            ))
+        );
+
+        // Test an unterminated one
+        let result = irstring(CompleteStr("'This is a test"));
+        assert_eq!(result.is_ok(), false);
+    }
+
+    #[test]
+    fn test_parse_label_operand() {
+        let result = operand(CompleteStr("@loop"));
+        assert_eq!(
+            result,
+            Ok((
+                CompleteStr(""),
+                Token::LabelUsage {
+                    name: "loop".to_string()
+                }
+            ))
+        );
+    }
 }
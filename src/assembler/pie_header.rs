@@ -0,0 +1,211 @@
+use std::fmt;
+
+use super::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX, PIE_VERSION};
+
+/// Layout of the fixed-size header at the start of every PIE file. All fields are
+/// big-endian, and all offsets are from the start of the file:
+///
+/// | bytes  | field                                    |
+/// |--------|------------------------------------------|
+/// | 0..4   | magic, `PIE_HEADER_PREFIX`               |
+/// | 4..6   | format version                           |
+/// | 6..8   | flags, currently always 0                |
+/// | 8..16  | code section offset and length           |
+/// | 16..24 | read-only data section offset and length |
+/// | 24..28 | entry point offset                       |
+/// | 28..36 | symbol section offset and length         |
+/// | 36..44 | debug section offset and length          |
+/// | 44..64 | reserved, zero                           |
+///
+/// Optional sections have an offset and length of 0 when absent.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct PieHeader {
+    pub version: u16,
+    pub flags: u16,
+    pub code_offset: u32,
+    pub code_length: u32,
+    pub data_offset: u32,
+    pub data_length: u32,
+    pub entry_point: u32,
+    pub symbol_offset: u32,
+    pub symbol_length: u32,
+    pub debug_offset: u32,
+    pub debug_length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PieHeaderError {
+    /// The file is shorter than a header
+    Truncated,
+    /// The file does not start with `PIE_HEADER_PREFIX`
+    BadMagic,
+    /// The file was written for a format version this build does not understand
+    UnsupportedVersion { version: u16 },
+    /// A section or the entry point lies outside of the file
+    SectionOutOfBounds,
+}
+
+impl fmt::Display for PieHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PieHeaderError::Truncated => write!(f, "file is too short to hold a PIE header"),
+            PieHeaderError::BadMagic => write!(f, "file does not start with the PIE magic"),
+            PieHeaderError::UnsupportedVersion { version } => write!(
+                f,
+                "PIE version {} is not supported (expected {})",
+                version, PIE_VERSION
+            ),
+            PieHeaderError::SectionOutOfBounds => {
+                write!(f, "PIE header points outside of the file")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PieHeaderError {}
+
+impl PieHeader {
+    /// A header for a file whose code directly follows the header and read-only data
+    pub fn new(data_length: u32, code_length: u32) -> PieHeader {
+        let code_offset = PIE_HEADER_LENGTH as u32 + data_length;
+        PieHeader {
+            version: PIE_VERSION,
+            flags: 0,
+            code_offset,
+            code_length,
+            data_offset: PIE_HEADER_LENGTH as u32,
+            data_length,
+            entry_point: code_offset,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = vec![];
+        for byte in PIE_HEADER_PREFIX.iter() {
+            header.push(*byte);
+        }
+        header.extend_from_slice(&self.version.to_be_bytes());
+        header.extend_from_slice(&self.flags.to_be_bytes());
+        for field in &[
+            self.code_offset,
+            self.code_length,
+            self.data_offset,
+            self.data_length,
+            self.entry_point,
+            self.symbol_offset,
+            self.symbol_length,
+            self.debug_offset,
+            self.debug_length,
+        ] {
+            header.extend_from_slice(&field.to_be_bytes());
+        }
+        while header.len() < PIE_HEADER_LENGTH {
+            header.push(0);
+        }
+        header
+    }
+
+    /// Reads the header at the start of `program` and checks that it describes `program`
+    pub fn parse(program: &[u8]) -> Result<PieHeader, PieHeaderError> {
+        if program.len() < PIE_HEADER_LENGTH {
+            return Err(PieHeaderError::Truncated);
+        }
+        if !program.starts_with(&PIE_HEADER_PREFIX) {
+            return Err(PieHeaderError::BadMagic);
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([program[i], program[i + 1]]);
+        let u32_at = |i: usize| {
+            u32::from_be_bytes([program[i], program[i + 1], program[i + 2], program[i + 3]])
+        };
+        let version = u16_at(4);
+        if version != PIE_VERSION {
+            return Err(PieHeaderError::UnsupportedVersion { version });
+        }
+        let header = PieHeader {
+            version,
+            flags: u16_at(6),
+            code_offset: u32_at(8),
+            code_length: u32_at(12),
+            data_offset: u32_at(16),
+            data_length: u32_at(20),
+            entry_point: u32_at(24),
+            symbol_offset: u32_at(28),
+            symbol_length: u32_at(32),
+            debug_offset: u32_at(36),
+            debug_length: u32_at(40),
+        };
+
+        let in_bounds =
+            |offset: u32, length: u32| offset as u64 + length as u64 <= program.len() as u64;
+        if !in_bounds(header.code_offset, header.code_length)
+            || !in_bounds(header.data_offset, header.data_length)
+            || !in_bounds(header.symbol_offset, header.symbol_length)
+            || !in_bounds(header.debug_offset, header.debug_length)
+            || header.entry_point < header.code_offset
+            || header.entry_point > header.code_end() as u32
+        {
+            return Err(PieHeaderError::SectionOutOfBounds);
+        }
+        Ok(header)
+    }
+
+    /// Offset of the first byte after the code section
+    pub fn code_end(&self) -> usize {
+        self.code_offset as usize + self.code_length as usize
+    }
+
+    /// The read-only data section of `program`
+    pub fn data<'a>(&self, program: &'a [u8]) -> &'a [u8] {
+        let start = self.data_offset as usize;
+        &program[start..start + self.data_length as usize]
+    }
+
+    /// The symbol section of `program`, if it has one
+    pub fn symbols<'a>(&self, program: &'a [u8]) -> Option<&'a [u8]> {
+        if self.symbol_length == 0 {
+            return None;
+        }
+        let start = self.symbol_offset as usize;
+        Some(&program[start..start + self.symbol_length as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut header = PieHeader::new(10, 8);
+        header.entry_point += 4;
+        let mut bytes = header.to_bytes();
+        assert_eq!(bytes.len(), PIE_HEADER_LENGTH);
+        bytes.resize(PIE_HEADER_LENGTH + 18, 0);
+        assert_eq!(PieHeader::parse(&bytes), Ok(header));
+    }
+
+    #[test]
+    fn test_header_errors() {
+        let header = PieHeader::new(0, 4);
+        let mut bytes = header.to_bytes();
+        assert_eq!(
+            PieHeader::parse(&bytes),
+            Err(PieHeaderError::SectionOutOfBounds)
+        );
+        bytes.extend_from_slice(&[5, 0, 0, 0]);
+        assert!(PieHeader::parse(&bytes).is_ok());
+
+        bytes[5] = 9;
+        assert_eq!(
+            PieHeader::parse(&bytes),
+            Err(PieHeaderError::UnsupportedVersion { version: 9 })
+        );
+        bytes[0] = 0;
+        assert_eq!(PieHeader::parse(&bytes), Err(PieHeaderError::BadMagic));
+        assert_eq!(
+            PieHeader::parse(&bytes[..10]),
+            Err(PieHeaderError::Truncated)
+        );
+    }
+}
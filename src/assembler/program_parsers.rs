@@ -1,8 +1,11 @@
+use nom::alphanumeric1;
 use nom::types::CompleteStr;
 
 use super::{
+    assembler_errors::{AssembleError, AssemblerError},
     instruction_parsers::{instruction, AssemblerInstruction},
-    SymbolTable,
+    label_parsers::label_declaration,
+    Location, Opcode, SymbolTable, Token,
 };
 
 #[derive(Debug, PartialEq)]
@@ -11,25 +14,101 @@ pub struct Program {
 }
 
 impl Program {
-    pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut program = vec![];
-        for instruction in &self.instructions {
-            program.append(&mut instruction.to_bytes(symbols));
+        for instruction in self.instructions.iter().filter(|i| i.is_opcode()) {
+            program.append(&mut instruction.to_bytes(symbols)?);
         }
-        program
+        Ok(program)
     }
 }
 
-named!(pub program<CompleteStr, Program>,
-    do_parse!(
-        instructions: many1!(instruction) >>
-        (
-            Program {
-                instructions
+/// Parses a whole source file, one instruction or directive per line
+pub fn program(input: CompleteStr) -> Result<Program, Vec<AssembleError>> {
+    let (program, errors) = parse_program(&input);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(program)
+}
+
+/// Parses every line of `source`, collecting an error for each line that does not parse
+/// instead of stopping at the first one. A line with an error still contributes its label,
+/// if it has one, so that uses of the label are not reported as well.
+pub fn parse_program(source: &str) -> (Program, Vec<AssembleError>) {
+    let mut instructions = vec![];
+    let mut errors = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let location = |offset: usize| Location {
+            line: index + 1,
+            column: line[..offset].chars().count() + 1,
+        };
+        match parse_line(line) {
+            Ok(None) => {}
+            Ok(Some(mut i)) => {
+                i.location = location(line.len() - line.trim_start().len());
+                instructions.push(i);
             }
-        )
-    )
-);
+            Err((offset, error)) => {
+                errors.push(AssembleError::new(error, location(offset), source));
+                if let Ok((_, label)) = label_declaration(CompleteStr(line.trim_start())) {
+                    instructions.push(AssemblerInstruction {
+                        opcode: None,
+                        label: Some(label),
+                        directive: None,
+                        operand1: None,
+                        operand2: None,
+                        operand3: None,
+                        location: location(line.len() - line.trim_start().len()),
+                    });
+                }
+            }
+        }
+    }
+    (Program { instructions }, errors)
+}
+
+/// Parses a single line, returning the byte offset into the line of anything wrong with it
+fn parse_line(line: &str) -> Result<Option<AssemblerInstruction>, (usize, AssemblerError)> {
+    let start = line.trim_start();
+    if start.is_empty() {
+        return Ok(None);
+    }
+    let offset_of = |rest: &str| line.len() - rest.len();
+    let (rest, i) = match instruction(CompleteStr(start)) {
+        Ok(parsed) => parsed,
+        Err(_) => return Err((offset_of(start), unexpected_input(start))),
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() {
+        return Err((offset_of(rest), unexpected_input(rest)));
+    }
+    // Unknown mnemonics parse as IGL, but only `igl` itself is meant to
+    if let Some(Token::Op { code: Opcode::IGL }) = i.opcode {
+        let (mnemonic, _) = opt!(CompleteStr(start), label_declaration).unwrap();
+        if let Ok((_, name)) = alphanumeric1::<CompleteStr>(mnemonic) {
+            if name.to_lowercase() != "igl" {
+                return Err((
+                    offset_of(&mnemonic),
+                    AssemblerError::UnknownMnemonic {
+                        name: name.to_string(),
+                    },
+                ));
+            }
+        }
+    }
+    Ok(Some(i))
+}
+
+/// Describes the first word of input that could not be parsed
+fn unexpected_input(input: &str) -> AssemblerError {
+    let word = input.split_whitespace().next().unwrap_or(input).to_string();
+    if word.starts_with('$') {
+        AssemblerError::BadRegister { register: word }
+    } else {
+        AssemblerError::UnexpectedInput { input: word }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -39,21 +118,70 @@ mod tests {
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100"));
         assert_eq!(result.is_ok(), true);
-        let (leftover, p) = result.unwrap();
-        assert_eq!(leftover, CompleteStr(""));
+        let p = result.unwrap();
         assert_eq!(1, p.instructions.len());
         // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
     }
 
+    #[test]
+    fn test_parse_multiline_program() {
+        let result = program(CompleteStr("load $0 #100\nsub: push $0\nret\n"));
+        let p = result.unwrap();
+        assert_eq!(3, p.instructions.len());
+        assert_eq!(p.instructions[1].get_label_name(), Some("sub".to_string()));
+        assert_eq!(p.instructions[2].location, Location { line: 3, column: 1 });
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let result = program(CompleteStr("load $0 #100"));
         assert_eq!(result.is_ok(), true);
-        let (_, program) = result.unwrap();
+        let program = result.unwrap();
         let symbols = SymbolTable::new();
-        let bytecode = program.to_bytes(&symbols);
+        let bytecode = program.to_bytes(&symbols).unwrap();
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
         // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
     }
+
+    #[test]
+    fn test_parse_program_errors() {
+        let (p, errors) = parse_program("lod $0 #1\nloop: add $0 $40 $1\n  hlt junk\n#5\n");
+        let errors: Vec<(Location, AssemblerError)> =
+            errors.into_iter().map(|e| (e.location, e.error)).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    Location { line: 1, column: 1 },
+                    AssemblerError::UnknownMnemonic {
+                        name: "lod".to_string()
+                    }
+                ),
+                (
+                    Location {
+                        line: 2,
+                        column: 14
+                    },
+                    AssemblerError::BadRegister {
+                        register: "$40".to_string()
+                    }
+                ),
+                (
+                    Location { line: 3, column: 7 },
+                    AssemblerError::UnexpectedInput {
+                        input: "junk".to_string()
+                    }
+                ),
+                (
+                    Location { line: 4, column: 1 },
+                    AssemblerError::UnexpectedInput {
+                        input: "#5".to_string()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(p.instructions.len(), 1);
+        assert_eq!(p.instructions[0].get_label_name(), Some("loop".to_string()));
+    }
 }
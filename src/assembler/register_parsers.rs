@@ -2,21 +2,33 @@ use nom::digit;
 use nom::types::CompleteStr;
 
 pub use crate::assembler::Token;
+use crate::vm::REGISTER_COUNT;
 
+// Looks for an integer register such as `$0`, or a float register such as `$f0`
 named!(pub register <CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
+            float: opt!(tag!("f")) >>
+            reg_num: register_number >>
             (
-                Token::Register{
-                  reg_num: reg_num.parse::<u8>().unwrap()
+                if float.is_some() {
+                    Token::FloatRegister{reg_num}
+                } else {
+                    Token::Register{reg_num}
                 }
             )
         )
     )
 );
 
+named!(register_number<CompleteStr, u8>,
+    verify!(
+        map_res!(digit, |d: CompleteStr| d.parse::<u8>()),
+        |n: u8| (n as usize) < REGISTER_COUNT
+    )
+);
+
 #[cfg(test)]
 mod tests {
 
@@ -30,5 +42,22 @@ mod tests {
         assert_eq!(result.is_ok(), false);
         let result = register(CompleteStr("$a"));
         assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$32"));
+        assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$300"));
+        assert_eq!(result.is_ok(), false);
+    }
+
+    #[test]
+    fn test_parse_float_register() {
+        let result = register(CompleteStr("$f31"));
+        assert_eq!(
+            result,
+            Ok((CompleteStr(""), Token::FloatRegister { reg_num: 31 }))
+        );
+        let result = register(CompleteStr("$f32"));
+        assert_eq!(result.is_ok(), false);
+        let result = register(CompleteStr("$f"));
+        assert_eq!(result.is_ok(), false);
     }
 }
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use crate::assembler::pie_header::{PieHeader, PieHeaderError};
+use crate::assembler::{Symbol, SymbolTable, SymbolType};
+use crate::instruction::{Opcode, OperandKind, INSTRUCTION_LENGTH};
+
+/// Renders a PIE program as assembly that assembles back to the same bytes. Labels are
+/// named after the symbol section when the program has one; otherwise call targets and
+/// the entry point get synthesised `L_0040`-style names, and constants `D_0000`-style
+/// ones.
+pub fn disassemble(program: &[u8]) -> Result<String, PieHeaderError> {
+    let header = PieHeader::parse(program)?;
+    let code = &program[header.code_offset as usize..header.code_end()];
+    let symbols = header.symbols(program).and_then(SymbolTable::from_bytes);
+
+    let mut labels = Labels::default();
+    match &symbols {
+        Some(symbols) => {
+            for symbol in symbols.symbols() {
+                let name = symbol.name().to_string();
+                match symbol.symbol_type() {
+                    SymbolType::Label => labels.code.insert(symbol.offset(), name),
+                    SymbolType::Import => labels.imports.insert(symbol.offset(), name),
+                    _ => labels.data.insert(symbol.offset(), name),
+                };
+            }
+        }
+        None => {
+            let mut targets: Vec<u32> = code
+                .chunks(INSTRUCTION_LENGTH)
+                .filter_map(|i| operand_value(i, OperandKind::Address))
+                .filter(|t| *t >= header.code_offset && (*t as usize) < header.code_end())
+                .collect();
+            if header.entry_point != header.code_offset {
+                targets.push(header.entry_point);
+            }
+            for target in targets {
+                labels.code.insert(target, format!("L_{:04X}", target));
+            }
+        }
+    }
+
+    // `loadf64 $f0 #0` would read back as the float 0, so constants without a name get one
+    let mut unnamed_constants = vec![];
+    for offset in code
+        .chunks(INSTRUCTION_LENGTH)
+        .filter_map(|i| operand_value(i, OperandKind::Constant))
+    {
+        if offset < header.data_length && !labels.data.contains_key(&offset) {
+            let name = format!("D_{:04X}", offset);
+            labels.data.insert(offset, name.clone());
+            unnamed_constants.push(Symbol::new(name, SymbolType::Byte, offset));
+        }
+    }
+
+    let mut lines = vec![];
+    if header.data_length > 0 {
+        lines.push(".data".to_string());
+        let mut data_symbols: Vec<&Symbol> = match &symbols {
+            Some(symbols) => symbols
+                .symbols()
+                .iter()
+                .filter(|s| !matches!(s.symbol_type(), SymbolType::Label | SymbolType::Import))
+                .collect(),
+            None => vec![],
+        };
+        data_symbols.extend(&unnamed_constants);
+        lines.append(&mut disassemble_data(header.data(program), data_symbols));
+        lines.push(".code".to_string());
+    }
+    for (i, instruction) in code.chunks(INSTRUCTION_LENGTH).enumerate() {
+        let address = header.code_offset + (i * INSTRUCTION_LENGTH) as u32;
+        let text = render_instruction(instruction, &labels);
+        match labels.code.get(&address) {
+            Some(name) => lines.push(format!("{}: {}", name, text)),
+            None => lines.push(text),
+        }
+    }
+    if header.entry_point != header.code_offset {
+        match labels.code.get(&header.entry_point) {
+            Some(name) => lines.push(format!(".entry @{}", name)),
+            None => lines.push(format!(".entry @L_{:04X}", header.entry_point)),
+        }
+    }
+
+    let mut output = lines.join("\n");
+    output.push('\n');
+    Ok(output)
+}
+
+/// Renders bytecode without a header, such as the program the REPL builds, one
+/// instruction per line
+pub fn disassemble_code(code: &[u8]) -> String {
+    let mut output = String::new();
+    for instruction in code.chunks(INSTRUCTION_LENGTH) {
+        output.push_str(&disassemble_instruction(instruction));
+        output.push('\n');
+    }
+    output
+}
+
+/// Renders a single encoded instruction, such as `load $0 #100`
+pub fn disassemble_instruction(bytes: &[u8]) -> String {
+    render_instruction(bytes, &Labels::default())
+}
+
+/// Names for the values of address and constant operands
+#[derive(Default)]
+struct Labels {
+    /// Code labels, by absolute address
+    code: HashMap<u32, String>,
+    /// Data labels, by offset into the read-only data
+    data: HashMap<u32, String>,
+    /// Names of host functions, by index in the imports
+    imports: HashMap<u32, String>,
+}
+
+fn render_instruction(bytes: &[u8], labels: &Labels) -> String {
+    let byte = |i: usize| bytes.get(i).cloned().unwrap_or(0);
+    let opcode = Opcode::from(byte(0));
+    let mut text = opcode.to_string();
+    let mut position = 1;
+    for kind in opcode.operands() {
+        match kind {
+            OperandKind::Register => text.push_str(&format!(" ${}", byte(position))),
+            OperandKind::FloatRegister => text.push_str(&format!(" $f{}", byte(position))),
+            OperandKind::Immediate => {
+                let value = i16::from_be_bytes([byte(position), byte(position + 1)]);
+                text.push_str(&format!(" #{}", value));
+            }
+            OperandKind::Offset => text.push_str(&format!(" #{}", byte(position))),
+            OperandKind::HostFunction => {
+                let value = u16::from_be_bytes([byte(position), byte(position + 1)]);
+                text.push_str(&format!(" #{}", value));
+            }
+            OperandKind::Import => {
+                let value = u16::from_be_bytes([byte(position), byte(position + 1)]);
+                match labels.imports.get(&(value as u32)) {
+                    Some(name) => text.push_str(&format!(" '{}'", name)),
+                    None => text.push_str(&format!(" #{}", value)),
+                }
+            }
+            OperandKind::Address | OperandKind::Constant => {
+                let value = u16::from_be_bytes([byte(position), byte(position + 1)]);
+                let names = if *kind == OperandKind::Address {
+                    &labels.code
+                } else {
+                    &labels.data
+                };
+                match names.get(&(value as u32)) {
+                    Some(name) => text.push_str(&format!(" @{}", name)),
+                    None => text.push_str(&format!(" #{}", value)),
+                }
+            }
+        }
+        position += kind.encoded_len();
+    }
+    text
+}
+
+/// Value of the first operand of `wanted` kind of an instruction, such as the target of
+/// a CALL
+fn operand_value(bytes: &[u8], wanted: OperandKind) -> Option<u32> {
+    let mut position = 1;
+    for kind in Opcode::from(*bytes.first()?).operands() {
+        if *kind == wanted {
+            let value = u16::from_be_bytes([*bytes.get(position)?, *bytes.get(position + 1)?]);
+            return Some(value as u32);
+        }
+        position += kind.encoded_len();
+    }
+    None
+}
+
+/// Renders the read-only data section as directives, using the symbol types to pick the
+/// directive each label was declared with
+fn disassemble_data(data: &[u8], mut symbols: Vec<&Symbol>) -> Vec<String> {
+    symbols.sort_by_key(|s| s.offset());
+    symbols.retain(|s| s.offset() as usize <= data.len());
+
+    let mut lines = vec![];
+    let mut cursor = 0;
+    for (i, symbol) in symbols.iter().enumerate() {
+        let start = symbol.offset() as usize;
+        let end = match symbols.get(i + 1) {
+            Some(next) => next.offset() as usize,
+            None => data.len(),
+        };
+        lines.append(&mut disassemble_bytes(&data[cursor..start]));
+        let region = &data[start..end];
+        let (directive, used) = data_directive(*symbol.symbol_type(), region);
+        lines.push(format!("{}: {}", symbol.name(), directive));
+        lines.append(&mut disassemble_bytes(&region[used..]));
+        cursor = end;
+    }
+    lines.append(&mut disassemble_bytes(&data[cursor..]));
+    lines
+}
+
+/// Renders the start of `region` as the directive `symbol_type` was declared with,
+/// returning the directive and how many bytes it covers
+fn data_directive(symbol_type: SymbolType, region: &[u8]) -> (String, usize) {
+    match symbol_type {
+        SymbolType::IrString => {
+            if let Some(end) = region.iter().position(|b| *b == 0) {
+                if let Ok(text) = std::str::from_utf8(&region[..end]) {
+                    if !text.contains('\'') {
+                        return (format!(".asciiz '{}'", text), end + 1);
+                    }
+                }
+            }
+        }
+        SymbolType::Word => {
+            if region.len() >= 4 {
+                let value = i32::from_be_bytes([region[0], region[1], region[2], region[3]]);
+                return (format!(".word #{}", value), 4);
+            }
+        }
+        SymbolType::Float => {
+            if let Some(bytes) = region.get(..8) {
+                let mut float = [0; 8];
+                float.copy_from_slice(bytes);
+                let value = f64::from_be_bytes(float);
+                // Debug formatting prints the shortest text that parses back to `value`
+                if value.is_finite() {
+                    return (format!(".f64 #{:?}", value), 8);
+                }
+            }
+        }
+        SymbolType::Space => {
+            let zeros = region.iter().take_while(|b| **b == 0).count();
+            return (format!(".space #{}", zeros), zeros);
+        }
+        SymbolType::Byte | SymbolType::Label | SymbolType::Import => {}
+    }
+    match region.first() {
+        Some(byte) => (format!(".byte #{}", byte), 1),
+        None => (".space #0".to_string(), 0),
+    }
+}
+
+/// Renders unlabelled data, with runs of zeros as `.space`
+fn disassemble_bytes(bytes: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0 {
+            let zeros = bytes[i..].iter().take_while(|b| **b == 0).count();
+            lines.push(format!(".space #{}", zeros));
+            i += zeros;
+        } else {
+            lines.push(format!(".byte #{}", bytes[i]));
+            i += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assert_round_trip(source: &str, strip_symbols: bool) -> String {
+        let mut asm = Assembler::new();
+        asm.strip_symbols = strip_symbols;
+        let program = asm.assemble(source).unwrap();
+        let text = disassemble(&program).unwrap();
+        let mut asm = Assembler::new();
+        asm.strip_symbols = strip_symbols;
+        assert_eq!(asm.assemble(&text).unwrap(), program, "{}", text);
+        text
+    }
+
+    #[test]
+    fn test_disassemble_instruction() {
+        assert_eq!(disassemble_instruction(&[0, 1, 1, 244]), "load $1 #500");
+        assert_eq!(disassemble_instruction(&[1, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(disassemble_instruction(&[5, 0, 0, 0]), "hlt");
+        assert_eq!(disassemble_instruction(&[44, 3, 4, 5]), "sar $3 $4 $5");
+        assert_eq!(disassemble_instruction(&[41, 1, 2, 0]), "not $1 $2");
+        assert_eq!(
+            disassemble_instruction(&[51, 1, 2, 200]),
+            "loadw $1 $2 #200"
+        );
+        assert_eq!(disassemble_instruction(&[17, 0, 1, 0]), "aloc $0 $1");
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $0 #100\ninc $0\nhlt\n").unwrap();
+        assert_eq!(
+            disassemble(&program).unwrap(),
+            "load $0 #100\ninc $0\nhlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let text = assert_round_trip(
+            ".data\nmsg: .asciiz 'Hello'\nn: .word #-258\n.byte #3\nbuf: .space #4\n\
+             .code\nmain: call @double\nhlt\ndouble: load $0 #-2\nret\n.entry @main\n",
+            false,
+        );
+        assert_eq!(
+            text,
+            ".data\nmsg: .asciiz 'Hello'\nn: .word #-258\n.byte #3\nbuf: .space #4\n\
+             .code\nmain: call @double\nhlt\ndouble: load $0 #-2\nret\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_synthesises_labels() {
+        let text = assert_round_trip(
+            ".data\n.byte #1\n.space #2\n.code\nhlt\nstart: call @sub\nhlt\nsub: ret\n\
+             .entry @start\n",
+            true,
+        );
+        assert_eq!(
+            text,
+            ".data\n.byte #1\n.space #2\n.code\nhlt\nL_0047: call @L_004F\nhlt\n\
+             L_004F: ret\n.entry @L_0047\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_floats() {
+        let source = ".data\npi: .f64 #3.14159\n.code\nloadf64 $f0 @pi\nloadf64 $f1 #-1e-7\n\
+                      addf64 $f0 $f1 $f2\nftoi $3 $f2\n";
+        let text = assert_round_trip(source, false);
+        assert_eq!(
+            text,
+            ".data\npi: .f64 #3.14159\n__f64_be7ad7f29abcaf48: .f64 #-1e-7\n.code\n\
+             loadf64 $f0 @pi\nloadf64 $f1 @__f64_be7ad7f29abcaf48\naddf64 $f0 $f1 $f2\n\
+             ftoi $3 $f2\n"
+        );
+        let text = assert_round_trip(source, true);
+        assert!(text.contains("loadf64 $f0 @D_0000\nloadf64 $f1 @D_0008\n"));
+    }
+
+    #[test]
+    fn test_disassemble_host_calls() {
+        let text = assert_round_trip("syscall 'print'\nsyscall #3\nhlt\n", true);
+        assert_eq!(text, "callhost 'print'\nsyscall #3\nhlt\n");
+    }
+
+    #[test]
+    fn test_disassemble_print() {
+        let source = ".data\nmsg: .asciiz 'Hi'\n.code\nprts @msg\nprti $0\nprtc $1\nhlt\n";
+        assert_eq!(assert_round_trip(source, false), source);
+    }
+
+    #[test]
+    fn test_disassemble_code() {
+        assert_eq!(
+            disassemble_code(&[0, 1, 2, 3, 5, 0, 0, 0]),
+            "load $1 #515\nhlt\n"
+        );
+    }
+}
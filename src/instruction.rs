@@ -1,6 +1,12 @@
+use std::fmt;
+
 use nom::types::CompleteStr;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+/// Every instruction is encoded as an opcode byte followed by three operand bytes,
+/// padded with zeros when the operands need fewer
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Opcode {
     LOAD,
     ADD,
@@ -22,9 +28,225 @@ pub enum Opcode {
     ALOC,
     INC,
     DEC,
+    PUSH,
+    POP,
+    CALL,
+    RET,
+    LUI,
+    LOADF64,
+    ADDF64,
+    SUBF64,
+    MULF64,
+    DIVF64,
+    EQF64,
+    NEQF64,
+    GTF64,
+    GTEF64,
+    LTF64,
+    LTEF64,
+    ITOF,
+    FTOI,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    JMPO,
+    JMPNO,
+    MOD,
+    MFR,
+    LOADB,
+    STOREB,
+    LOADW,
+    STOREW,
+    FREE,
+    JMPNE,
+    JMPFE,
+    JMPBE,
+    MOV,
+    ADDI,
+    SUBI,
+    MULI,
+    DIVI,
+    EQI,
+    NEQI,
+    GTI,
+    GTEI,
+    LTI,
+    LTEI,
+    SYSCALL,
+    CALLHOST,
+    PRTI,
+    PRTS,
+    PRTC,
     IGL,
 }
 
+/// Kinds of operand an instruction can take
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    /// A register such as `$0`, encoded in one byte
+    Register,
+    /// A signed integer such as `#-100` or a label address such as `@loop`, encoded in
+    /// two bytes
+    Immediate,
+    /// An unsigned program address such as `@sub` or `#72`, encoded in two bytes
+    Address,
+    /// A floating point register such as `$f0`, encoded in one byte
+    FloatRegister,
+    /// The label of data such as an `.f64` constant or `.asciiz` string, or a float
+    /// such as `#3.14`, encoded as the data's two-byte offset into the read-only data
+    Constant,
+    /// An unsigned offset from a heap address such as `#4`, encoded in one byte
+    Offset,
+    /// The number of a host function registered on the VM, such as `#2`, encoded in two
+    /// bytes
+    HostFunction,
+    /// The name of a host function such as `'print'`, encoded as the two-byte index of
+    /// the name in the program's imports, which the VM resolves when it loads the program
+    Import,
+}
+
+impl OperandKind {
+    /// Number of bytes the operand occupies in an encoded instruction
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::Offset => 1,
+            OperandKind::Immediate
+            | OperandKind::Address
+            | OperandKind::Constant
+            | OperandKind::HostFunction
+            | OperandKind::Import => 2,
+        }
+    }
+
+    /// Smallest and largest values an integer operand of this kind can hold
+    pub fn range(&self) -> (i64, i64) {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::Offset => {
+                (0, u8::MAX as i64)
+            }
+            OperandKind::Immediate => (i16::MIN as i64, i16::MAX as i64),
+            OperandKind::Address
+            | OperandKind::Constant
+            | OperandKind::HostFunction
+            | OperandKind::Import => (0, u16::MAX as i64),
+        }
+    }
+}
+
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "register"),
+            OperandKind::Immediate => write!(f, "immediate"),
+            OperandKind::Address => write!(f, "address"),
+            OperandKind::FloatRegister => write!(f, "float register"),
+            OperandKind::Constant => write!(f, "constant"),
+            OperandKind::Offset => write!(f, "offset"),
+            OperandKind::HostFunction => write!(f, "host function number"),
+            OperandKind::Import => write!(f, "host function name"),
+        }
+    }
+}
+
+impl Opcode {
+    /// Operands the instruction takes, in the order they are written and encoded
+    pub fn operands(&self) -> &'static [OperandKind] {
+        use self::OperandKind::*;
+        match self {
+            Opcode::LOAD => &[Register, Immediate],
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::MOD => {
+                &[Register, Register, Register]
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::GTE | Opcode::LT | Opcode::LTE => {
+                &[Register, Register]
+            }
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JMPE
+            | Opcode::JMPNE
+            | Opcode::JMPFE
+            | Opcode::JMPBE
+            | Opcode::JMPO
+            | Opcode::JMPNO
+            | Opcode::MFR
+            | Opcode::FREE
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::PUSH
+            | Opcode::POP
+            | Opcode::PRTI
+            | Opcode::PRTC => &[Register],
+            Opcode::CALL => &[Address],
+            Opcode::SYSCALL => &[HostFunction],
+            Opcode::CALLHOST => &[Import],
+            Opcode::PRTS => &[Constant],
+            Opcode::LUI
+            | Opcode::ADDI
+            | Opcode::SUBI
+            | Opcode::MULI
+            | Opcode::DIVI
+            | Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::GTEI
+            | Opcode::LTI
+            | Opcode::LTEI => &[Register, Immediate],
+            Opcode::LOADF64 => &[FloatRegister, Constant],
+            Opcode::ADDF64 | Opcode::SUBF64 | Opcode::MULF64 | Opcode::DIVF64 => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::EQF64
+            | Opcode::NEQF64
+            | Opcode::GTF64
+            | Opcode::GTEF64
+            | Opcode::LTF64
+            | Opcode::LTEF64 => &[FloatRegister, FloatRegister],
+            Opcode::ITOF => &[FloatRegister, Register],
+            Opcode::FTOI => &[Register, FloatRegister],
+            Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+                &[Register, Register, Register]
+            }
+            Opcode::NOT | Opcode::ALOC | Opcode::MOV => &[Register, Register],
+            Opcode::LOADB | Opcode::STOREB | Opcode::LOADW | Opcode::STOREW => {
+                &[Register, Register, Offset]
+            }
+            Opcode::HLT | Opcode::NOP | Opcode::RET | Opcode::IGL => &[],
+        }
+    }
+
+    /// The opcode the assembler uses instead of this one when the operands fit it but not
+    /// this one, such as ADDI for `add $0 #1` or CALLHOST for `syscall 'print'`. The
+    /// immediate forms take a register and an immediate, so `add $0 #1` adds 1 to `$0` in
+    /// place and there is no `add $0 $1 #2`.
+    pub fn alternate_form(&self) -> Option<Opcode> {
+        match self {
+            Opcode::SYSCALL => Some(Opcode::CALLHOST),
+            Opcode::ADD => Some(Opcode::ADDI),
+            Opcode::SUB => Some(Opcode::SUBI),
+            Opcode::MUL => Some(Opcode::MULI),
+            Opcode::DIV => Some(Opcode::DIVI),
+            Opcode::EQ => Some(Opcode::EQI),
+            Opcode::NEQ => Some(Opcode::NEQI),
+            Opcode::GT => Some(Opcode::GTI),
+            Opcode::GTE => Some(Opcode::GTEI),
+            Opcode::LT => Some(Opcode::LTI),
+            Opcode::LTE => Some(Opcode::LTEI),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Instruction {
     opcode: Opcode,
@@ -59,11 +281,145 @@ impl From<u8> for Opcode {
             17 => Opcode::ALOC,
             18 => Opcode::INC,
             19 => Opcode::DEC,
+            20 => Opcode::PUSH,
+            21 => Opcode::POP,
+            22 => Opcode::CALL,
+            23 => Opcode::RET,
+            24 => Opcode::LUI,
+            25 => Opcode::LOADF64,
+            26 => Opcode::ADDF64,
+            27 => Opcode::SUBF64,
+            28 => Opcode::MULF64,
+            29 => Opcode::DIVF64,
+            30 => Opcode::EQF64,
+            31 => Opcode::NEQF64,
+            32 => Opcode::GTF64,
+            33 => Opcode::GTEF64,
+            34 => Opcode::LTF64,
+            35 => Opcode::LTEF64,
+            36 => Opcode::ITOF,
+            37 => Opcode::FTOI,
+            38 => Opcode::AND,
+            39 => Opcode::OR,
+            40 => Opcode::XOR,
+            41 => Opcode::NOT,
+            42 => Opcode::SHL,
+            43 => Opcode::SHR,
+            44 => Opcode::SAR,
+            45 => Opcode::JMPO,
+            46 => Opcode::JMPNO,
+            47 => Opcode::MOD,
+            48 => Opcode::MFR,
+            49 => Opcode::LOADB,
+            50 => Opcode::STOREB,
+            51 => Opcode::LOADW,
+            52 => Opcode::STOREW,
+            53 => Opcode::FREE,
+            54 => Opcode::JMPNE,
+            55 => Opcode::JMPFE,
+            56 => Opcode::JMPBE,
+            57 => Opcode::MOV,
+            58 => Opcode::ADDI,
+            59 => Opcode::SUBI,
+            60 => Opcode::MULI,
+            61 => Opcode::DIVI,
+            62 => Opcode::EQI,
+            63 => Opcode::NEQI,
+            64 => Opcode::GTI,
+            65 => Opcode::GTEI,
+            66 => Opcode::LTI,
+            67 => Opcode::LTEI,
+            68 => Opcode::SYSCALL,
+            69 => Opcode::CALLHOST,
+            70 => Opcode::PRTI,
+            71 => Opcode::PRTS,
+            72 => Opcode::PRTC,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl From<Opcode> for u8 {
+    fn from(op: Opcode) -> Self {
+        match op {
+            Opcode::LOAD => 0,
+            Opcode::ADD => 1,
+            Opcode::SUB => 2,
+            Opcode::MUL => 3,
+            Opcode::DIV => 4,
+            Opcode::HLT => 5,
+            Opcode::JMP => 6,
+            Opcode::JMPF => 7,
+            Opcode::JMPB => 8,
+            Opcode::EQ => 9,
+            Opcode::NEQ => 10,
+            Opcode::GT => 11,
+            Opcode::GTE => 12,
+            Opcode::LT => 13,
+            Opcode::LTE => 14,
+            Opcode::JMPE => 15,
+            Opcode::NOP => 16,
+            Opcode::ALOC => 17,
+            Opcode::INC => 18,
+            Opcode::DEC => 19,
+            Opcode::PUSH => 20,
+            Opcode::POP => 21,
+            Opcode::CALL => 22,
+            Opcode::RET => 23,
+            Opcode::LUI => 24,
+            Opcode::LOADF64 => 25,
+            Opcode::ADDF64 => 26,
+            Opcode::SUBF64 => 27,
+            Opcode::MULF64 => 28,
+            Opcode::DIVF64 => 29,
+            Opcode::EQF64 => 30,
+            Opcode::NEQF64 => 31,
+            Opcode::GTF64 => 32,
+            Opcode::GTEF64 => 33,
+            Opcode::LTF64 => 34,
+            Opcode::LTEF64 => 35,
+            Opcode::ITOF => 36,
+            Opcode::FTOI => 37,
+            Opcode::AND => 38,
+            Opcode::OR => 39,
+            Opcode::XOR => 40,
+            Opcode::NOT => 41,
+            Opcode::SHL => 42,
+            Opcode::SHR => 43,
+            Opcode::SAR => 44,
+            Opcode::JMPO => 45,
+            Opcode::JMPNO => 46,
+            Opcode::MOD => 47,
+            Opcode::MFR => 48,
+            Opcode::LOADB => 49,
+            Opcode::STOREB => 50,
+            Opcode::LOADW => 51,
+            Opcode::STOREW => 52,
+            Opcode::FREE => 53,
+            Opcode::JMPNE => 54,
+            Opcode::JMPFE => 55,
+            Opcode::JMPBE => 56,
+            Opcode::MOV => 57,
+            Opcode::ADDI => 58,
+            Opcode::SUBI => 59,
+            Opcode::MULI => 60,
+            Opcode::DIVI => 61,
+            Opcode::EQI => 62,
+            Opcode::NEQI => 63,
+            Opcode::GTI => 64,
+            Opcode::GTEI => 65,
+            Opcode::LTI => 66,
+            Opcode::LTEI => 67,
+            Opcode::SYSCALL => 68,
+            Opcode::CALLHOST => 69,
+            Opcode::PRTI => 70,
+            Opcode::PRTS => 71,
+            Opcode::PRTC => 72,
+            Opcode::IGL => 255,
+        }
+    }
+}
+
 impl<'a> From<CompleteStr<'a>> for Opcode {
     fn from(v: CompleteStr<'a>) -> Self {
         match v {
@@ -87,6 +443,59 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("inc") => Opcode::INC,
             CompleteStr("dec") => Opcode::DEC,
             CompleteStr("nop") => Opcode::NOP,
+            CompleteStr("push") => Opcode::PUSH,
+            CompleteStr("pop") => Opcode::POP,
+            CompleteStr("call") => Opcode::CALL,
+            CompleteStr("ret") => Opcode::RET,
+            CompleteStr("lui") => Opcode::LUI,
+            CompleteStr("loadf64") => Opcode::LOADF64,
+            CompleteStr("addf64") => Opcode::ADDF64,
+            CompleteStr("subf64") => Opcode::SUBF64,
+            CompleteStr("mulf64") => Opcode::MULF64,
+            CompleteStr("divf64") => Opcode::DIVF64,
+            CompleteStr("eqf64") => Opcode::EQF64,
+            CompleteStr("neqf64") => Opcode::NEQF64,
+            CompleteStr("gtf64") => Opcode::GTF64,
+            CompleteStr("gtef64") => Opcode::GTEF64,
+            CompleteStr("ltf64") => Opcode::LTF64,
+            CompleteStr("ltef64") => Opcode::LTEF64,
+            CompleteStr("itof") => Opcode::ITOF,
+            CompleteStr("ftoi") => Opcode::FTOI,
+            CompleteStr("and") => Opcode::AND,
+            CompleteStr("or") => Opcode::OR,
+            CompleteStr("xor") => Opcode::XOR,
+            CompleteStr("not") => Opcode::NOT,
+            CompleteStr("shl") => Opcode::SHL,
+            CompleteStr("shr") => Opcode::SHR,
+            CompleteStr("sar") => Opcode::SAR,
+            CompleteStr("jmpo") => Opcode::JMPO,
+            CompleteStr("jmpno") => Opcode::JMPNO,
+            CompleteStr("mod") => Opcode::MOD,
+            CompleteStr("mfr") => Opcode::MFR,
+            CompleteStr("loadb") => Opcode::LOADB,
+            CompleteStr("storeb") => Opcode::STOREB,
+            CompleteStr("loadw") => Opcode::LOADW,
+            CompleteStr("storew") => Opcode::STOREW,
+            CompleteStr("free") => Opcode::FREE,
+            CompleteStr("jmpne") => Opcode::JMPNE,
+            CompleteStr("jmpfe") => Opcode::JMPFE,
+            CompleteStr("jmpbe") => Opcode::JMPBE,
+            CompleteStr("mov") => Opcode::MOV,
+            CompleteStr("addi") => Opcode::ADDI,
+            CompleteStr("subi") => Opcode::SUBI,
+            CompleteStr("muli") => Opcode::MULI,
+            CompleteStr("divi") => Opcode::DIVI,
+            CompleteStr("eqi") => Opcode::EQI,
+            CompleteStr("neqi") => Opcode::NEQI,
+            CompleteStr("gti") => Opcode::GTI,
+            CompleteStr("gtei") => Opcode::GTEI,
+            CompleteStr("lti") => Opcode::LTI,
+            CompleteStr("ltei") => Opcode::LTEI,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("callhost") => Opcode::CALLHOST,
+            CompleteStr("prti") => Opcode::PRTI,
+            CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("prtc") => Opcode::PRTC,
             _ => Opcode::IGL,
         }
     }
@@ -115,4 +524,39 @@ mod tests {
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_operand_signatures_fit_instruction() {
+        for byte in 0..=255u8 {
+            let opcode = Opcode::from(byte);
+            let length: usize = 1 + opcode
+                .operands()
+                .iter()
+                .map(|o| o.encoded_len())
+                .sum::<usize>();
+            assert!(length <= INSTRUCTION_LENGTH, "{} is too wide", opcode);
+        }
+    }
+
+    #[test]
+    fn test_opcode_to_u8_round_trip() {
+        for byte in 0..=255u8 {
+            let opcode = Opcode::from(byte);
+            if opcode != Opcode::IGL {
+                assert_eq!(u8::from(opcode), byte);
+            }
+        }
+        assert_eq!(Opcode::from(u8::from(Opcode::IGL)), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_opcode_mnemonic_round_trip() {
+        for byte in 0..=255u8 {
+            let opcode = Opcode::from(byte);
+            if opcode != Opcode::IGL {
+                let mnemonic = opcode.to_string();
+                assert_eq!(Opcode::from(CompleteStr(&mnemonic)), opcode);
+            }
+        }
+    }
 }
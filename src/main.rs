@@ -1,8 +1,9 @@
+// The parser tests compare results against `true` and `false` explicitly
+#![allow(clippy::bool_assert_comparison)]
+
 use std::fs::File;
-use std::{
-    io::{prelude::*, Read},
-    path::Path,
-};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
 
 #[macro_use]
 extern crate nom;
@@ -10,35 +11,68 @@ extern crate nom;
 #[macro_use]
 extern crate clap;
 
-use clap::{App, Arg, SubCommand};
+use clap::App;
 
 pub mod assembler;
+pub mod disassembler;
 pub mod instruction;
+pub mod profiler;
 pub mod repl;
 pub mod vm;
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target_file = matches.value_of("INPUT_FILE");
-    match target_file {
-        Some(filename) => {
-            let program = read_file(filename);
-            let mut asm = assembler::Assembler::new();
+    match matches.subcommand() {
+        ("assemble", Some(sub)) => {
+            let input = sub.value_of("INPUT_FILE").unwrap();
+            let output = match sub.value_of("OUTPUT_FILE") {
+                Some(output) => output.to_string(),
+                None => Path::new(input)
+                    .with_extension("pie")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            assemble_file(input, &output, sub.is_present("STRIP"));
+        }
+        ("run", Some(sub)) => {
             let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
-            match program {
-                Some(p) => {
-                    vm.add_bytes(p);
-                    vm.run();
-                    std::process::exit(0);
-                }
-                None => {}
+            vm.overflow_mode = match sub.value_of("OVERFLOW") {
+                Some("trap") => vm::OverflowMode::Trap,
+                Some("saturate") => vm::OverflowMode::Saturate,
+                _ => vm::OverflowMode::Wrap,
+            };
+            vm.trace = match sub.value_of("TRACE_FILE") {
+                Some(path) => match File::create(path) {
+                    Ok(file) => Some(Box::new(BufWriter::new(file))),
+                    Err(e) => {
+                        println!("There was an error creating {}: {:?}", path, e);
+                        std::process::exit(1);
+                    }
+                },
+                None if sub.is_present("TRACE") => Some(Box::new(std::io::stderr())),
+                None => None,
+            };
+            if sub.is_present("PROFILE") || sub.is_present("PROFILE_JSON") {
+                vm.profile = Some(profiler::Profile::new());
             }
+            let max_steps = if sub.is_present("MAX_STEPS") {
+                Some(value_t!(sub, "MAX_STEPS", u64).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
+            run_file(
+                sub.value_of("INPUT_FILE").unwrap(),
+                vm,
+                max_steps,
+                sub.value_of("PROFILE_JSON"),
+            );
         }
-        None => {
-            start_repl();
-        }
+        ("disasm", Some(sub)) => disassemble_file(sub.value_of("INPUT_FILE").unwrap()),
+        _ => match matches.value_of("INPUT_FILE") {
+            Some(filename) => run_file(filename, vm::VM::new(), None, None),
+            None => start_repl(),
+        },
     }
 }
 
@@ -51,15 +85,122 @@ fn start_repl() {
     repl.run(reader, writer);
 }
 
-fn read_file(tmp: &str) -> String {
+/// Assembles the source in `input` and writes the bytecode to `output`, leaving out the
+/// symbol section when `strip_symbols` is set
+fn assemble_file(input: &str, output: &str, strip_symbols: bool) {
+    let source = read_source(input);
+    let program = assemble_or_exit(input, &source, strip_symbols);
+    if let Err(e) = std::fs::write(output, program) {
+        println!("There was an error writing {}: {:?}", output, e);
+        std::process::exit(1);
+    }
+}
+
+/// Runs either a .pie bytecode file or an assembly source file, telling them apart by the
+/// PIE header, on a VM configured by the command line options, for at most `max_steps`
+/// instructions if given. If the VM is profiling, the profile is written to `profile_json`
+/// or printed to stderr afterwards.
+fn run_file(filename: &str, mut vm: vm::VM, max_steps: Option<u64>, profile_json: Option<&str>) {
+    let bytes = read_file(filename);
+    let program = if bytes.starts_with(&assembler::PIE_HEADER_PREFIX) {
+        bytes
+    } else {
+        assemble_or_exit(filename, &bytes_to_source(bytes), false)
+    };
+    vm.load_program(program);
+    let result = match max_steps {
+        Some(steps) => {
+            vm.costs = vm::CostTable::uniform(1);
+            vm.run_with_budget(steps)
+        }
+        None => vm.run(),
+    };
+    // Dropping the trace sink flushes a trace file before we exit
+    vm.trace = None;
+    let _ = vm.output.flush();
+    if let Some(profile) = vm.profile.take() {
+        write_profile(&profile, &vm.program, profile_json);
+    }
+    match result {
+        Ok(vm::ExitStatus::OutOfFuel) => {
+            println!(
+                "The program did not finish within {} steps",
+                max_steps.unwrap()
+            );
+            std::process::exit(1);
+        }
+        Ok(_) => std::process::exit(0),
+        Err(e) => {
+            println!("There was an error running the program: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reports a profile, naming addresses after the labels in the program's symbol section
+fn write_profile(profile: &profiler::Profile, program: &[u8], json_path: Option<&str>) {
+    let symbols = assembler::pie_header::PieHeader::parse(program)
+        .ok()
+        .and_then(|header| header.symbols(program))
+        .and_then(assembler::SymbolTable::from_bytes);
+    match json_path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, profile.to_json(symbols.as_ref())) {
+                println!("There was an error writing {}: {:?}", path, e);
+            }
+        }
+        None => eprint!("{}", profile.report(symbols.as_ref())),
+    }
+}
+
+fn disassemble_file(filename: &str) {
+    let program = read_file(filename);
+    match disassembler::disassemble(&program) {
+        Ok(text) => print!("{}", text),
+        Err(e) => {
+            println!("There was an error disassembling {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn assemble_or_exit(filename: &str, source: &str, strip_symbols: bool) -> Vec<u8> {
+    let mut asm = assembler::Assembler::new();
+    asm.strip_symbols = strip_symbols;
+    asm.file_name = Some(filename.to_string());
+    match asm.assemble(source) {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in &errors {
+                println!("{}\n", e);
+            }
+            println!("{} error(s) assembling {}", errors.len(), filename);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_source(tmp: &str) -> String {
+    bytes_to_source(read_file(tmp))
+}
+
+fn bytes_to_source(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("There was an error reading file: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_file(tmp: &str) -> Vec<u8> {
     let filename = Path::new(tmp);
     match File::open(Path::new(&filename)) {
         Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
-                Ok(_) => {
-                    return contents;
-                }
+            let mut contents = vec![];
+            match fh.read_to_end(&mut contents) {
+                Ok(_) => contents,
                 Err(e) => {
                     println!("There was an error reading file: {:?}", e);
                     std::process::exit(1);
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::assembler::{SymbolTable, SymbolType};
+use crate::instruction::Opcode;
+
+/// Execution counts gathered by the VM while its `profile` is set
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    /// Number of instructions executed
+    pub instructions: u64,
+    /// Wall time spent running, not counting time paused at breakpoints or between
+    /// REPL commands
+    pub elapsed: Duration,
+    opcodes: HashMap<Opcode, u64>,
+    addresses: HashMap<usize, u64>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Counts one execution of `opcode` at `address`
+    pub fn record(&mut self, address: usize, opcode: Opcode) {
+        self.instructions += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        *self.addresses.entry(address).or_insert(0) += 1;
+    }
+
+    /// How many times each opcode was executed, most executed first
+    pub fn opcode_counts(&self) -> Vec<(Opcode, u64)> {
+        let mut counts: Vec<(Opcode, u64)> = self.opcodes.iter().map(|(o, c)| (*o, *c)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(u8::from(a.0).cmp(&u8::from(b.0))));
+        counts
+    }
+
+    /// How many times the instruction at each address was executed, most executed first
+    pub fn address_counts(&self) -> Vec<(usize, u64)> {
+        let mut counts: Vec<(usize, u64)> = self.addresses.iter().map(|(a, c)| (*a, *c)).collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// A table of the counts for people to read, naming addresses after the nearest label
+    /// before them when `symbols` is given
+    pub fn report(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut report = format!(
+            "Executed {} instructions in {:?}\n\nCount       Opcode\n",
+            self.instructions, self.elapsed
+        );
+        for (opcode, count) in self.opcode_counts() {
+            report.push_str(&format!("{:<11} {}\n", count, opcode));
+        }
+        report.push_str("\nCount       Address\n");
+        for (address, count) in self.address_counts() {
+            match label_for(address, symbols) {
+                Some(label) => {
+                    report.push_str(&format!("{:<11} {:04} {}\n", count, address, label))
+                }
+                None => report.push_str(&format!("{:<11} {:04}\n", count, address)),
+            }
+        }
+        report
+    }
+
+    /// The counts as a JSON object, for other tools to read
+    pub fn to_json(&self, symbols: Option<&SymbolTable>) -> String {
+        let opcodes: Vec<String> = self
+            .opcode_counts()
+            .iter()
+            .map(|(opcode, count)| format!("{{\"opcode\":\"{}\",\"count\":{}}}", opcode, count))
+            .collect();
+        let addresses: Vec<String> = self
+            .address_counts()
+            .iter()
+            .map(|(address, count)| {
+                let label = match label_for(*address, symbols) {
+                    Some(label) => json_string(&label),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"address\":{},\"label\":{},\"count\":{}}}",
+                    address, label, count
+                )
+            })
+            .collect();
+        format!(
+            "{{\"instructions\":{},\"elapsed_ns\":{},\"opcodes\":[{}],\"addresses\":[{}]}}",
+            self.instructions,
+            self.elapsed.as_nanos(),
+            opcodes.join(","),
+            addresses.join(",")
+        )
+    }
+}
+
+/// Quotes `text` as a JSON string. Labels come from the program's symbol section, so
+/// they may hold quotes, backslashes or control characters.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Names `address` after the closest code label at or before it, such as `loop+4`
+fn label_for(address: usize, symbols: Option<&SymbolTable>) -> Option<String> {
+    let label = symbols?
+        .symbols()
+        .iter()
+        .filter(|s| *s.symbol_type() == SymbolType::Label && s.offset() as usize <= address)
+        .max_by_key(|s| s.offset())?;
+    let offset = address - label.offset() as usize;
+    if offset == 0 {
+        Some(label.name().to_string())
+    } else {
+        Some(format!("{}+{}", label.name(), offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Symbol;
+
+    fn sample() -> (Profile, SymbolTable) {
+        let mut profile = Profile::new();
+        profile.record(64, Opcode::LOAD);
+        for _ in 0..3 {
+            profile.record(68, Opcode::INC);
+            profile.record(72, Opcode::JMP);
+        }
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 68));
+        (profile, symbols)
+    }
+
+    #[test]
+    fn test_counts() {
+        let (profile, _) = sample();
+        assert_eq!(profile.instructions, 7);
+        assert_eq!(
+            profile.opcode_counts(),
+            vec![(Opcode::JMP, 3), (Opcode::INC, 3), (Opcode::LOAD, 1)]
+        );
+        assert_eq!(profile.address_counts(), vec![(68, 3), (72, 3), (64, 1)]);
+    }
+
+    #[test]
+    fn test_report() {
+        let (profile, symbols) = sample();
+        assert_eq!(
+            profile.report(Some(&symbols)),
+            "Executed 7 instructions in 0ns\n\nCount       Opcode\n3           jmp\n\
+             3           inc\n1           load\n\nCount       Address\n3           0068 loop\n\
+             3           0072 loop+4\n1           0064\n"
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let (profile, symbols) = sample();
+        assert_eq!(
+            profile.to_json(Some(&symbols)),
+            "{\"instructions\":7,\"elapsed_ns\":0,\"opcodes\":[{\"opcode\":\"jmp\",\"count\":3},\
+             {\"opcode\":\"inc\",\"count\":3},{\"opcode\":\"load\",\"count\":1}],\
+             \"addresses\":[{\"address\":68,\"label\":\"loop\",\"count\":3},\
+             {\"address\":72,\"label\":\"loop+4\",\"count\":3},\
+             {\"address\":64,\"label\":null,\"count\":1}]}"
+        );
+    }
+
+    #[test]
+    fn test_to_json_escapes_labels() {
+        let mut profile = Profile::new();
+        profile.record(64, Opcode::HLT);
+        let mut symbols = SymbolTable::new();
+        let name = "a\"b\\c\nd\u{1}".to_string();
+        symbols.add_symbol(Symbol::new(name, SymbolType::Label, 64));
+        assert_eq!(
+            profile.to_json(Some(&symbols)),
+            "{\"instructions\":1,\"elapsed_ns\":0,\"opcodes\":[{\"opcode\":\"hlt\",\"count\":1}],\
+             \"addresses\":[{\"address\":64,\"label\":\"a\\\"b\\\\c\\nd\\u0001\",\"count\":1}]}"
+        );
+    }
+}
@@ -1,20 +1,38 @@
-use nom::types::CompleteStr;
-
-use crate::assembler::program_parsers::program;
-use crate::assembler::Assembler;
+use crate::assembler::program_parsers::{program, Program};
+use crate::assembler::{Assembler, Symbol, SymbolType};
+use crate::disassembler::{disassemble, disassemble_code, disassemble_instruction};
+use crate::instruction::{Opcode, INSTRUCTION_LENGTH};
 pub use crate::vm::VM;
+use crate::vm::{ExitStatus, VmError};
 use std;
-use std::fs::File;
-use std::io::{BufRead, Read, Write};
+use std::io::{BufRead, Write};
 use std::num::ParseIntError;
-use std::path::Path;
+use std::sync::{Arc, Mutex};
 // use std::num::ParseIntError;
 // use std::result::Result;
 
+/// Trace lines from the VM, held until the REPL copies them to its writer
+#[derive(Clone, Default)]
+struct TraceBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
 pub struct REPL {
     command_buffer: Vec<String>,
     vm: VM,
     asm: Assembler,
+    /// Where the VM writes its trace while `.trace on` is in effect
+    trace: Option<TraceBuffer>,
 }
 
 impl REPL {
@@ -23,6 +41,7 @@ impl REPL {
             vm: VM::new(),
             command_buffer: vec![],
             asm: Assembler::new(),
+            trace: None,
         }
     }
 
@@ -46,14 +65,24 @@ impl REPL {
         let mut buffer = String::new();
         write!(&mut writer, ">>> ").expect("Unable to write");
         writer.flush().unwrap();
-        reader
+        let read = reader
             .read_line(&mut buffer)
             .expect("Unable to read line from user");
+        if read == 0 {
+            // End of input, e.g. ctrl-d
+            return true;
+        }
         let buffer = buffer.trim();
 
         self.command_buffer.push(buffer.to_string());
 
-        match buffer {
+        // Debugger commands such as `.step 3` take an argument after the command
+        let (command, argument) = match buffer.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (buffer, ""),
+        };
+
+        match command {
             ".quit" => {
                 writeln!(&mut writer, "Farewell! Have a great day!")
                     .expect("Unable to execute .quit");
@@ -70,10 +99,12 @@ impl REPL {
             ".program" => {
                 println!("Listing instructions currently in VM's program vector:");
                 writer.flush().unwrap();
-                for instruction in &self.vm.program {
-                    writeln!(&mut writer, "{}", instruction).expect("Unable to execute .program");
-                    writer.flush().unwrap();
-                }
+                let listing = match disassemble(&self.vm.program) {
+                    Ok(text) => text,
+                    Err(_) => disassemble_code(&self.vm.program),
+                };
+                write!(&mut writer, "{}", listing).expect("Unable to execute .program");
+                writer.flush().unwrap();
                 writeln!(&mut writer, "End of Program Listing")
                     .expect("Unable to write ending message of  .program");
                 writer.flush().unwrap();
@@ -101,40 +132,261 @@ impl REPL {
                     .read_line(&mut tmp)
                     .expect("Unable to read line from user");
                 let tmp = tmp.trim();
-                let filename = Path::new(&tmp);
-                let mut f = File::open(Path::new(&filename)).expect("File not found");
-                let mut contents = String::new();
-                f.read_to_string(&mut contents)
-                    .expect("There was an error reading from the file");
-                let program = match program(CompleteStr(&contents)) {
-                    Ok((_remainder, program)) => program,
+                let contents = match std::fs::read_to_string(tmp) {
+                    Ok(contents) => contents,
                     Err(e) => {
-                        writeln!(&mut writer, "Unable to parse input: {:?}", e).unwrap();
+                        writeln!(&mut writer, "Unable to read {}: {}", tmp, e).unwrap();
                         return false;
                     }
                 };
-                self.vm
-                    .program
-                    .append(&mut program.to_bytes(&self.asm.symbol_table));
+                // The file is a whole program, with its data, entry point and imports, so it
+                // replaces the one in the VM rather than being appended to it
+                let mut asm = Assembler::new();
+                asm.file_name = Some(tmp.to_string());
+                match asm.assemble(&contents) {
+                    Ok(bytes) => self.vm.load_program(bytes),
+                    Err(errors) => {
+                        for e in errors {
+                            writeln!(&mut writer, "{}", e).unwrap();
+                        }
+                        return false;
+                    }
+                }
+                // Keep the file's labels for `.break` and friends
+                self.asm = asm;
+                match self.vm.start() {
+                    Ok(_) => {
+                        writeln!(&mut writer, "Loaded {}", tmp).unwrap();
+                        self.show_current_instruction(&mut writer);
+                    }
+                    Err(e) => writeln!(&mut writer, "Error: {}", e).unwrap(),
+                }
+                false
+            }
+            ".break" => {
+                match self.parse_address(argument) {
+                    Some(address) => {
+                        self.vm.breakpoints.insert(address);
+                        writeln!(&mut writer, "Breakpoint set at {}", address).unwrap();
+                    }
+                    None => {
+                        writeln!(&mut writer, "Unknown address or label: {}", argument).unwrap()
+                    }
+                }
+                false
+            }
+            ".delete" => {
+                if argument.is_empty() {
+                    self.vm.breakpoints.clear();
+                    writeln!(&mut writer, "Deleted all breakpoints").unwrap();
+                } else {
+                    match self.parse_address(argument) {
+                        Some(address) if self.vm.breakpoints.remove(&address) => {
+                            writeln!(&mut writer, "Deleted breakpoint at {}", address).unwrap()
+                        }
+                        _ => writeln!(&mut writer, "No breakpoint at {}", argument).unwrap(),
+                    }
+                }
+                false
+            }
+            ".step" => {
+                let steps = if argument.is_empty() {
+                    Ok(1)
+                } else {
+                    argument.parse::<usize>()
+                };
+                match steps {
+                    Ok(steps) => {
+                        let mut result = Ok(ExitStatus::Running);
+                        for _ in 0..steps {
+                            result = self.vm.run_once();
+                            if result != Ok(ExitStatus::Running) {
+                                break;
+                            }
+                        }
+                        self.report_stop(result, &mut writer);
+                    }
+                    Err(_) => writeln!(&mut writer, "Usage: .step [count]").unwrap(),
+                }
+                false
+            }
+            ".next" => {
+                let pc = self.vm.pc();
+                let result = match self.vm.program.get(pc).map(|b| Opcode::from(*b)) {
+                    // Run the whole subroutine by pausing at the instruction after the CALL
+                    Some(Opcode::CALL) => {
+                        let return_address = pc + INSTRUCTION_LENGTH;
+                        let added = self.vm.breakpoints.insert(return_address);
+                        let result = self.vm.resume();
+                        if added {
+                            self.vm.breakpoints.remove(&return_address);
+                            if result == Ok(ExitStatus::Breakpoint)
+                                && self.vm.pc() == return_address
+                            {
+                                self.write_trace(&mut writer);
+                                self.show_current_instruction(&mut writer);
+                                return false;
+                            }
+                        }
+                        result
+                    }
+                    _ => self.vm.run_once(),
+                };
+                self.report_stop(result, &mut writer);
+                false
+            }
+            ".continue" => {
+                let result = self.vm.resume();
+                self.report_stop(result, &mut writer);
+                false
+            }
+            ".pc" => {
+                self.show_current_instruction(&mut writer);
+                false
+            }
+            ".save_state" => {
+                if argument.is_empty() {
+                    writeln!(&mut writer, "Usage: .save_state <path>").unwrap();
+                } else {
+                    match std::fs::write(argument, self.vm.snapshot()) {
+                        Ok(_) => writeln!(&mut writer, "Saved VM state to {}", argument).unwrap(),
+                        Err(e) => {
+                            writeln!(&mut writer, "Unable to write {}: {}", argument, e).unwrap()
+                        }
+                    }
+                }
+                false
+            }
+            ".load_state" => {
+                if argument.is_empty() {
+                    writeln!(&mut writer, "Usage: .load_state <path>").unwrap();
+                    return false;
+                }
+                match std::fs::read(argument) {
+                    Ok(snapshot) => match self.vm.restore(&snapshot) {
+                        Ok(_) => {
+                            writeln!(&mut writer, "Loaded VM state from {}", argument).unwrap();
+                            self.show_current_instruction(&mut writer);
+                        }
+                        Err(e) => writeln!(&mut writer, "Unable to load state: {}", e).unwrap(),
+                    },
+                    Err(e) => writeln!(&mut writer, "Unable to read {}: {}", argument, e).unwrap(),
+                }
+                false
+            }
+            ".trace" => {
+                match argument {
+                    "on" => {
+                        let buffer = TraceBuffer::default();
+                        self.vm.trace = Some(Box::new(buffer.clone()));
+                        self.trace = Some(buffer);
+                        writeln!(&mut writer, "Tracing enabled").unwrap();
+                    }
+                    "off" => {
+                        self.vm.trace = None;
+                        self.trace = None;
+                        writeln!(&mut writer, "Tracing disabled").unwrap();
+                    }
+                    _ => writeln!(&mut writer, "Usage: .trace on|off").unwrap(),
+                }
                 false
             }
             _ => {
                 let program = match program(buffer.into()) {
-                    Ok((_, program)) => program,
-                    Err(_) => {
-                        println!("Unable to parse input");
-                        return true;
+                    Ok(program) => program,
+                    Err(errors) => {
+                        for e in errors {
+                            writeln!(&mut writer, "{}", e).unwrap();
+                        }
+                        return false;
                     }
                 };
-                self.vm
-                    .program
-                    .append(&mut program.to_bytes(&self.asm.symbol_table));
-                self.vm.run_once();
+                self.declare_labels(&program);
+                match program.to_bytes(&self.asm.symbol_table) {
+                    Ok(mut bytes) => self.vm.program.append(&mut bytes),
+                    Err(e) => {
+                        writeln!(&mut writer, "Unable to assemble input: {}", e).unwrap();
+                        return false;
+                    }
+                }
+                let result = self.vm.run_once();
+                self.write_trace(&mut writer);
+                if let Err(e) = result {
+                    writeln!(&mut writer, "Error: {}", e).unwrap();
+                }
                 false
             }
         }
     }
 
+    /// Adds the labels `program` declares to the symbol table, at the addresses its
+    /// instructions will have once appended to the VM's program
+    fn declare_labels(&mut self, program: &Program) {
+        let mut address = self.vm.program.len();
+        for instruction in &program.instructions {
+            if let Some(name) = instruction.get_label_name() {
+                if self.asm.symbol_table.symbol(&name).is_none() {
+                    let symbol = Symbol::new(name, SymbolType::Label, address as u32);
+                    self.asm.symbol_table.add_symbol(symbol);
+                }
+            }
+            address += instruction.encoded_len();
+        }
+    }
+
+    /// Reads a breakpoint address written as a number, such as `8` or `0x40`, or a label
+    fn parse_address(&self, argument: &str) -> Option<usize> {
+        let argument = argument.trim_start_matches('@');
+        let address = match argument.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => argument.parse::<usize>().ok(),
+        };
+        address.or_else(|| {
+            self.asm
+                .symbol_table
+                .symbol_value(argument)
+                .map(|value| value as usize)
+        })
+    }
+
+    /// Says why the VM stopped, if it did, followed by the instruction it will run next
+    fn report_stop<W: Write>(&self, result: Result<ExitStatus, VmError>, mut writer: W) {
+        self.write_trace(&mut writer);
+        match result {
+            Ok(ExitStatus::Running) | Ok(ExitStatus::OutOfFuel) => {}
+            Ok(ExitStatus::Breakpoint) => writeln!(&mut writer, "Stopped at breakpoint").unwrap(),
+            Ok(ExitStatus::Halted) => writeln!(&mut writer, "Program halted").unwrap(),
+            Ok(ExitStatus::EndOfProgram) => {
+                writeln!(&mut writer, "Reached the end of the program").unwrap()
+            }
+            Err(e) => writeln!(&mut writer, "Error: {}", e).unwrap(),
+        }
+        self.show_current_instruction(&mut writer);
+    }
+
+    /// Copies the trace the VM wrote since the last call to `writer`
+    fn write_trace<W: Write>(&self, mut writer: W) {
+        if let Some(trace) = &self.trace {
+            let mut buffer = trace.0.lock().unwrap();
+            writer.write_all(&buffer).unwrap();
+            buffer.clear();
+        }
+    }
+
+    /// Prints the program counter and the decoded instruction at it, such as `=> 8: inc $0`
+    fn show_current_instruction<W: Write>(&self, mut writer: W) {
+        let pc = self.vm.pc();
+        // A loaded program's symbols follow its code
+        let code = &self.vm.program[..self.vm.code_end()];
+        match code.get(pc..pc + INSTRUCTION_LENGTH) {
+            Some(bytes) => {
+                writeln!(&mut writer, "=> {}: {}", pc, disassemble_instruction(bytes)).unwrap()
+            }
+            None => writeln!(&mut writer, "=> {}: end of program", pc).unwrap(),
+        }
+        writer.flush().unwrap();
+    }
+
     #[allow(dead_code)]
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
         let split = i.split(' ').collect::<Vec<&str>>();
@@ -197,7 +449,7 @@ mod tests {
         test_repl.vm.program = vec![0, 1, 2, 3];
         test_repl.run_once(&input[..], &mut output);
         let output = String::from_utf8(output).expect("Not UTF-8");
-        assert_eq!(">>> 0\n1\n2\n3\nEnd of Program Listing\n", output);
+        assert_eq!(">>> load $1 #515\nEnd of Program Listing\n", output);
     }
 
     #[test]
@@ -216,11 +468,139 @@ mod tests {
 
     #[test]
     fn test_run_load_file() {
-        let input = b".load_file\ntest.pie\n.quit\n";
+        let mut test_repl = REPL::new();
+        let output = run_commands(&mut test_repl, ".load_file\ntest.pie\n.step\n");
+        assert_eq!(
+            output,
+            ">>> Please enter the path to the file you wish to load: Loaded test.pie\n\
+             => 64: load $0 #100\n>>> => 68: end of program\n>>> "
+        );
+        assert_eq!(test_repl.vm.registers[0], 100);
+    }
+
+    #[test]
+    fn test_run_load_file_with_data() {
+        let path = std::env::temp_dir().join(format!("iridium-repl-{}.iasm", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            ".data\nmsg: .asciiz 'Hi'\n.code\nhlt\nstart: prts @msg\nhlt\n.entry @start\n",
+        )
+        .unwrap();
+        let mut test_repl = REPL::new();
+        let printed = TraceBuffer::default();
+        test_repl.vm.output = Box::new(printed.clone());
+        let output = run_commands(
+            &mut test_repl,
+            &format!(".load_file\n{}\n.break @start\n", path),
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                ">>> Please enter the path to the file you wish to load: Loaded {}\n\
+                 => 71: prts #0\n>>> Breakpoint set at 71\n>>> ",
+                path
+            )
+        );
+        let output = run_commands(&mut test_repl, ".step\n.continue\n");
+        assert_eq!(*printed.0.lock().unwrap(), b"Hi");
+        assert_eq!(
+            output,
+            ">>> => 75: hlt\n>>> Program halted\n=> 79: end of program\n"
+        );
+
+        let output = run_commands(&mut test_repl, &format!(".load_file\n{}\n", path));
+        assert!(output.contains("Unable to read"), "{}", output);
+    }
+
+    /// Appends `source` to the program the way typed instructions are, without running it
+    fn load(repl: &mut REPL, source: &str) {
+        let program = program(source.into()).unwrap();
+        repl.declare_labels(&program);
+        let mut bytes = program.to_bytes(&repl.asm.symbol_table).unwrap();
+        repl.vm.program.append(&mut bytes);
+    }
+
+    fn run_commands(repl: &mut REPL, commands: &str) -> String {
+        let mut input = commands.as_bytes();
         let mut output = Vec::new();
+        for _ in commands.lines() {
+            repl.run_once(&mut input, &mut output);
+        }
+        String::from_utf8(output).expect("Not UTF-8")
+    }
+
+    #[test]
+    fn test_run_break_continue_step() {
         let mut test_repl = REPL::new();
-        test_repl.run_once(&input[..], &mut output);
-        assert_eq!(test_repl.vm.program, vec![0, 0, 0, 100]);
+        load(&mut test_repl, "inc $0\ninc $0\ntwo: inc $1\ninc $1\nhlt\n");
+        let output = run_commands(&mut test_repl, ".break @two\n.continue\n.step 2\n.pc\n");
+        assert_eq!(
+            output,
+            ">>> Breakpoint set at 8\n>>> Stopped at breakpoint\n=> 8: inc $1\n\
+             >>> => 16: hlt\n>>> => 16: hlt\n"
+        );
+        assert_eq!(test_repl.vm.registers[0..2], [2, 2]);
+        let output = run_commands(&mut test_repl, ".continue\n");
+        assert_eq!(output, ">>> Program halted\n=> 20: end of program\n");
+    }
+
+    #[test]
+    fn test_run_next_and_delete() {
+        let mut test_repl = REPL::new();
+        load(&mut test_repl, "call @sub\nhlt\nsub: inc $0\nret\n");
+        let output = run_commands(
+            &mut test_repl,
+            ".break 0x8\n.delete sub\n.next\n.delete 8\n",
+        );
+        assert_eq!(
+            output,
+            ">>> Breakpoint set at 8\n>>> Deleted breakpoint at 8\n>>> => 4: hlt\n\
+             >>> No breakpoint at 8\n"
+        );
+        assert_eq!(test_repl.vm.registers[0], 1);
+        assert!(test_repl.vm.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_run_trace() {
+        let mut test_repl = REPL::new();
+        load(&mut test_repl, "load $0 #3\ninc $0\n");
+        let output = run_commands(&mut test_repl, ".trace on\n.trace\n.step 2\ndec $0\n");
+        assert_eq!(
+            output,
+            ">>> Tracing enabled\n>>> Usage: .trace on|off\n>>> 0000: load $0 #3 ; $0=3\n\
+             0004: inc $0 ; $0=4\n=> 8: end of program\n>>> 0008: dec $0 ; $0=3\n"
+        );
+        assert!(test_repl.vm.trace.is_some());
+        let output = run_commands(&mut test_repl, ".trace off\ninc $0\n");
+        assert_eq!(output, ">>> Tracing disabled\n>>> ");
+        assert!(test_repl.vm.trace.is_none());
+        assert_eq!(test_repl.vm.registers[0], 4);
+    }
+
+    #[test]
+    fn test_run_save_and_load_state() {
+        let path =
+            std::env::temp_dir().join(format!("iridium-repl-{}.snapshot", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut test_repl = REPL::new();
+        load(&mut test_repl, "inc $0\ninc $0\nhlt\n");
+        run_commands(&mut test_repl, ".step\n");
+        let output = run_commands(&mut test_repl, &format!(".save_state {}\n", path));
+        assert_eq!(output, format!(">>> Saved VM state to {}\n", path));
+
+        let mut other = REPL::new();
+        let output = run_commands(&mut other, &format!(".load_state {}\n", path));
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            output,
+            format!(">>> Loaded VM state from {}\n=> 4: inc $0\n", path)
+        );
+        assert_eq!(other.vm.registers[0], 1);
+        let output = run_commands(&mut other, &format!(".load_state {}\n", path));
+        assert!(output.starts_with(&format!(">>> Unable to read {}: ", path)));
     }
 
     // #[test]
@@ -1,175 +1,1260 @@
-use crate::{assembler, instruction::Opcode};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::time::Instant;
+
+use crate::{
+    assembler::pie_header::{PieHeader, PieHeaderError},
+    assembler::{SymbolTable, SymbolType},
+    disassembler::disassemble_instruction,
+    instruction::{Opcode, INSTRUCTION_LENGTH},
+    profiler::Profile,
+};
+
+/// Number of 32-bit slots in the VM-managed call stack
+pub const STACK_SIZE: usize = 1024;
+/// Number of general purpose registers, `$0` to `$31`
+pub const REGISTER_COUNT: usize = 32;
+/// Default for `VM::heap_limit`, 64 MiB
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Faults that stop the VM from executing a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// The program does not start with a valid PIE header
+    BadHeader,
+    /// The program was assembled for a PIE format version this VM does not support
+    UnsupportedVersion { version: u16 },
+    /// The byte at `pc` does not decode to a known opcode
+    IllegalOpcode { opcode: u8, pc: usize },
+    /// An instruction referred to a register the VM does not have
+    RegisterOutOfRange { register: u8, pc: usize },
+    /// A DIV instruction had zero as its divisor
+    DivisionByZero { pc: usize },
+    /// An integer operation overflowed while the VM was in `OverflowMode::Trap`
+    ArithmeticOverflow { pc: usize },
+    /// The program ended in the middle of the instruction starting at `pc`
+    TruncatedInstruction { pc: usize },
+    /// An instruction tried to grow or address the heap outside of its bounds
+    HeapOutOfBounds { address: i64, pc: usize },
+    /// A jump would have moved the program counter before byte 0, or outside of the code
+    /// section of a program with a header
+    InvalidJumpTarget { target: i64, pc: usize },
+    /// A LOADF64 or PRTS referred to data outside of the read-only data
+    ConstantOutOfBounds { offset: usize, pc: usize },
+    /// A PUSH or CALL did not fit in the stack region
+    StackOverflow { pc: usize },
+    /// A POP or RET found the stack empty
+    StackUnderflow { pc: usize },
+    /// The program imports a host function that was not registered on the VM
+    UnknownHostFunction { name: String },
+    /// A SYSCALL referred to a host function number that was never registered
+    InvalidSyscall { number: u16, pc: usize },
+    /// A CALLHOST referred to an import the program does not have
+    InvalidImport { index: u16, pc: usize },
+    /// A PRTI, PRTS or PRTC could not write to the VM's output
+    OutputFailed { message: String, pc: usize },
+    /// A host function returned an error
+    HostFunctionFailed {
+        name: String,
+        message: String,
+        pc: usize,
+    },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::BadHeader => write!(f, "program does not start with a valid PIE header"),
+            VmError::UnsupportedVersion { version } => {
+                write!(f, "unsupported PIE version {}", version)
+            }
+            VmError::IllegalOpcode { opcode, pc } => {
+                write!(f, "illegal opcode {} at {}", opcode, pc)
+            }
+            VmError::RegisterOutOfRange { register, pc } => {
+                write!(f, "register ${} out of range at {}", register, pc)
+            }
+            VmError::DivisionByZero { pc } => write!(f, "division by zero at {}", pc),
+            VmError::ArithmeticOverflow { pc } => write!(f, "arithmetic overflow at {}", pc),
+            VmError::TruncatedInstruction { pc } => {
+                write!(f, "program ends in the middle of the instruction at {}", pc)
+            }
+            VmError::HeapOutOfBounds { address, pc } => {
+                write!(f, "heap address {} out of bounds at {}", address, pc)
+            }
+            VmError::InvalidJumpTarget { target, pc } => {
+                write!(f, "invalid jump target {} at {}", target, pc)
+            }
+            VmError::ConstantOutOfBounds { offset, pc } => {
+                write!(
+                    f,
+                    "read-only data offset {} out of bounds at {}",
+                    offset, pc
+                )
+            }
+            VmError::StackOverflow { pc } => write!(f, "stack overflow at {}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "stack underflow at {}", pc),
+            VmError::UnknownHostFunction { name } => {
+                write!(f, "program imports unknown host function '{}'", name)
+            }
+            VmError::InvalidSyscall { number, pc } => {
+                write!(f, "no host function number {} at {}", number, pc)
+            }
+            VmError::InvalidImport { index, pc } => {
+                write!(f, "no import number {} at {}", index, pc)
+            }
+            VmError::OutputFailed { message, pc } => {
+                write!(f, "could not write output at {}: {}", pc, message)
+            }
+            VmError::HostFunctionFailed { name, message, pc } => {
+                write!(f, "host function '{}' failed at {}: {}", name, pc, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl From<PieHeaderError> for VmError {
+    fn from(e: PieHeaderError) -> Self {
+        match e {
+            PieHeaderError::UnsupportedVersion { version } => {
+                VmError::UnsupportedVersion { version }
+            }
+            _ => VmError::BadHeader,
+        }
+    }
+}
+
+/// What a host function called by a program can reach: the registers, which hold its
+/// arguments and results, the heap and the read-only data
+pub struct HostContext<'a> {
+    pub registers: &'a mut [i32; REGISTER_COUNT],
+    pub float_registers: &'a mut [f64; REGISTER_COUNT],
+    pub heap: &'a mut Vec<u8>,
+    pub ro_data: &'a [u8],
+}
+
+/// A function the host exposes to programs. An `Err` stops the program with
+/// `VmError::HostFunctionFailed`.
+pub type HostFunction = Box<dyn FnMut(&mut HostContext) -> Result<(), String> + Send>;
+
+/// Leading bytes of a VM snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"IRSN";
+/// Version of the snapshot format `VM::snapshot` writes
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Reasons `VM::restore` can reject a snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    /// The bytes do not start with `SNAPSHOT_MAGIC`
+    BadMagic,
+    /// The snapshot was written in a format version this VM does not read
+    UnsupportedVersion { version: u16 },
+    /// The snapshot ends before all of the state it describes
+    Truncated,
+    /// The snapshot holds values no VM could be in, such as an unknown overflow mode
+    Invalid,
+    /// The program imports a host function that was not registered on the VM
+    UnknownHostFunction { name: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a VM snapshot"),
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid => write!(f, "snapshot holds an invalid VM state"),
+            SnapshotError::UnknownHostFunction { name } => {
+                write!(f, "program imports unknown host function '{}'", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Reads the big-endian fields of a snapshot in order
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// A length-prefixed run of bytes
+    fn vec(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.u64()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Why the VM stopped executing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    /// The instruction completed and execution can continue
+    Running,
+    /// A HLT instruction was executed
+    Halted,
+    /// The program counter ran past the end of the program
+    EndOfProgram,
+    /// `run_with_budget` ran out of fuel before the next instruction. Running again
+    /// carries on from that instruction.
+    OutOfFuel,
+    /// The program counter reached one of the VM's breakpoints. Running again carries on
+    /// from the instruction at the breakpoint.
+    Breakpoint,
+}
+
+/// How much fuel each opcode costs `run_with_budget`. By default most instructions cost
+/// 1, multiplication and division 4, float arithmetic 2 and heap allocation 8.
+#[derive(Clone)]
+pub struct CostTable {
+    costs: [u64; 256],
+}
+
+impl CostTable {
+    /// A table where every instruction costs the same, so that a budget counts steps
+    pub fn uniform(cost: u64) -> CostTable {
+        CostTable { costs: [cost; 256] }
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[u8::from(opcode) as usize]
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[u8::from(opcode) as usize] = cost;
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let mut table = CostTable::uniform(1);
+        for opcode in [
+            Opcode::MUL,
+            Opcode::DIV,
+            Opcode::MOD,
+            Opcode::MULI,
+            Opcode::DIVI,
+        ] {
+            table.set_cost(opcode, 4);
+        }
+        for opcode in [
+            Opcode::ADDF64,
+            Opcode::SUBF64,
+            Opcode::MULF64,
+            Opcode::DIVF64,
+        ] {
+            table.set_cost(opcode, 2);
+        }
+        table.set_cost(Opcode::ALOC, 8);
+        table
+    }
+}
+
+/// What integer arithmetic does when its result does not fit in an `i32`. The overflow
+/// flag is set either way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowMode {
+    /// Stop with `VmError::ArithmeticOverflow`
+    Trap,
+    /// Keep the low 32 bits of the result, as two's complement hardware does
+    #[default]
+    Wrap,
+    /// Clamp the result to `i32::MIN` or `i32::MAX`
+    Saturate,
+}
 
 pub struct VM {
     /// Array that simulates having hardware registers
-    pub registers: [i32; 32],
+    pub registers: [i32; REGISTER_COUNT],
+    /// Floating point registers, `$f0` to `$f31`
+    pub float_registers: [f64; REGISTER_COUNT],
     /// The bytecode of the program being run
     pub program: Vec<u8>,
+    /// Header of the program, read when `run` starts executing it
+    header: Option<PieHeader>,
+    /// Read-only data section of the program, loaded from the PIE file
+    ro_data: Vec<u8>,
     heap: Vec<u8>,
+    /// Fixed-size region used by PUSH/POP and CALL/RET
+    stack: Vec<i32>,
+    /// Stack pointer: index of the next free slot in `stack`
+    sp: usize,
+    /// Frame pointer: value of `sp` when the current subroutine was entered
+    bp: usize,
     /// Program counter that tracks which byte is being executed
     pc: usize,
-    /// Contains the remainder of modulo division ops
-    remainder: usize,
+    /// Address of the instruction currently being executed, used to report faults
+    instruction_pc: usize,
+    /// Contains the remainder of the last DIV, which has the sign of the dividend
+    remainder: i32,
     /// Contains the result of the last comparison operation
     equal_flag: bool,
+    /// Whether the last ADD, SUB, MUL, DIV, INC or DEC overflowed
+    overflow_flag: bool,
+    /// How integer arithmetic handles overflow
+    pub overflow_mode: OverflowMode,
+    /// Fuel each opcode costs when running with a budget
+    pub costs: CostTable,
+    /// Largest the heap may grow to, in bytes. ALOC fails past it.
+    pub heap_limit: usize,
+    /// Addresses `run` pauses at before executing the instruction there
+    pub breakpoints: HashSet<usize>,
+    /// Where to write a line for every executed instruction, if anywhere
+    pub trace: Option<Box<dyn Write + Send>>,
+    /// Where PRTI, PRTS and PRTC write, stdout unless replaced
+    pub output: Box<dyn Write + Send>,
+    /// Execution counts, gathered only when this is set
+    pub profile: Option<Profile>,
+    /// Functions programs can call, numbered in the order they were registered
+    host_functions: Vec<(String, HostFunction)>,
+    /// Host function number of each of the program's imports, by import index
+    imports: HashMap<u32, usize>,
+}
+
+/// The state an instruction can change, compared before and after it when tracing
+struct TraceState {
+    registers: [i32; REGISTER_COUNT],
+    float_registers: [f64; REGISTER_COUNT],
+    remainder: i32,
+    equal_flag: bool,
+    overflow_flag: bool,
+    sp: usize,
+}
+
+impl TraceState {
+    fn capture(vm: &VM) -> TraceState {
+        TraceState {
+            registers: vm.registers,
+            float_registers: vm.float_registers,
+            remainder: vm.remainder,
+            equal_flag: vm.equal_flag,
+            overflow_flag: vm.overflow_flag,
+            sp: vm.sp,
+        }
+    }
+
+    /// Describes what differs in `after`, such as `$2=7 equal=true`
+    fn changes(&self, after: &TraceState) -> Vec<String> {
+        let mut changes = vec![];
+        for (i, (old, new)) in self.registers.iter().zip(&after.registers).enumerate() {
+            if old != new {
+                changes.push(format!("${}={}", i, new));
+            }
+        }
+        for (i, (old, new)) in self
+            .float_registers
+            .iter()
+            .zip(&after.float_registers)
+            .enumerate()
+        {
+            if old.to_bits() != new.to_bits() {
+                changes.push(format!("$f{}={:?}", i, new));
+            }
+        }
+        if self.remainder != after.remainder {
+            changes.push(format!("remainder={}", after.remainder));
+        }
+        if self.equal_flag != after.equal_flag {
+            changes.push(format!("equal={}", after.equal_flag));
+        }
+        if self.overflow_flag != after.overflow_flag {
+            changes.push(format!("overflow={}", after.overflow_flag));
+        }
+        if self.sp != after.sp {
+            changes.push(format!("sp={}", after.sp));
+        }
+        changes
+    }
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],
+            registers: [0; REGISTER_COUNT],
+            float_registers: [0.0; REGISTER_COUNT],
             program: vec![],
+            header: None,
+            ro_data: vec![],
             heap: vec![],
+            stack: vec![0; STACK_SIZE],
+            sp: 0,
+            bp: 0,
             pc: 0,
+            instruction_pc: 0,
             remainder: 0,
             equal_flag: false,
+            overflow_flag: false,
+            overflow_mode: OverflowMode::Wrap,
+            costs: CostTable::default(),
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            breakpoints: HashSet::new(),
+            trace: None,
+            output: Box::new(std::io::stdout()),
+            profile: None,
+            host_functions: vec![],
+            imports: HashMap::new(),
+        }
+    }
+
+    /// Runs the program until it halts, runs off its end, reaches a breakpoint or faults
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        self.start()?;
+        self.execute(None)
+    }
+
+    /// Runs the program like `run`, but stops with `ExitStatus::OutOfFuel` rather than
+    /// execute an instruction that costs more than is left of `fuel`
+    pub fn run_with_budget(&mut self, fuel: u64) -> Result<ExitStatus, VmError> {
+        self.start()?;
+        self.execute(Some(fuel))
+    }
+
+    /// Runs from the current program counter without reading a header, as the REPL does
+    /// with the instructions typed into it
+    pub fn resume(&mut self) -> Result<ExitStatus, VmError> {
+        self.execute(None)
+    }
+
+    /// Reads the header and jumps to the entry point if we have not started executing the
+    /// program yet. `run` does this itself; a debugger calls it to step a loaded program
+    /// from its entry point.
+    pub fn start(&mut self) -> Result<(), VmError> {
+        if self.header.is_none() {
+            self.load_header()?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, fuel: Option<u64>) -> Result<ExitStatus, VmError> {
+        if self.profile.is_none() {
+            return self.execute_loop(fuel);
+        }
+        let started = Instant::now();
+        let result = self.execute_loop(fuel);
+        if let Some(profile) = self.profile.as_mut() {
+            profile.elapsed += started.elapsed();
+        }
+        result
+    }
+
+    fn execute_loop(&mut self, mut fuel: Option<u64>) -> Result<ExitStatus, VmError> {
+        // The instruction we start at is not a breakpoint, or we could never continue
+        // past one
+        let mut first = true;
+        loop {
+            if !first && self.breakpoints.contains(&self.pc) {
+                return Ok(ExitStatus::Breakpoint);
+            }
+            first = false;
+            if let (Some(remaining), Some(byte)) = (fuel.as_mut(), self.program.get(self.pc)) {
+                let cost = self.costs.cost(Opcode::from(*byte));
+                if cost > *remaining {
+                    return Ok(ExitStatus::OutOfFuel);
+                }
+                *remaining -= cost;
+            }
+            match self.execute_instruction()? {
+                ExitStatus::Running => {}
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Executes a single instruction
+    pub fn run_once(&mut self) -> Result<ExitStatus, VmError> {
+        self.execute_instruction()
+    }
+
+    /// Makes `function` callable from programs, either as `syscall #n` with the number
+    /// this returns or as `syscall 'name'`. Functions should be registered before the
+    /// program is run, since its imports are resolved when it is loaded.
+    pub fn register_host_function<F>(&mut self, name: &str, function: F) -> u16
+    where
+        F: FnMut(&mut HostContext) -> Result<(), String> + Send + 'static,
+    {
+        self.host_functions
+            .push((name.to_string(), Box::new(function)));
+        (self.host_functions.len() - 1) as u16
+    }
+
+    /// Saves the state of the program being run, so that `restore` can carry on with it
+    /// later, possibly in another process. Settings of the host rather than the program,
+    /// such as breakpoints, the trace sink, the profile and the cost table, are left out.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        for register in &self.registers {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        for register in &self.float_registers {
+            bytes.extend_from_slice(&register.to_bits().to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.pc as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.remainder.to_be_bytes());
+        bytes.push(self.equal_flag as u8);
+        bytes.push(self.overflow_flag as u8);
+        bytes.push(match self.overflow_mode {
+            OverflowMode::Trap => 0,
+            OverflowMode::Wrap => 1,
+            OverflowMode::Saturate => 2,
+        });
+        // The header and read-only data are read from the program again on restore
+        bytes.push(self.header.is_some() as u8);
+        bytes.extend_from_slice(&(self.sp as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.bp as u64).to_be_bytes());
+        for slot in &self.stack[..self.sp] {
+            bytes.extend_from_slice(&slot.to_be_bytes());
+        }
+        for section in [&self.program, &self.heap] {
+            bytes.extend_from_slice(&(section.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(section);
         }
+        bytes
     }
 
-    pub fn run(&mut self) {
-        if !self.verify_header() {
-            std::process::exit(1);
+    /// Replaces the state of the program being run with one saved by `snapshot`. The VM is
+    /// left as it was if the snapshot cannot be read.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader { bytes: snapshot };
+        if reader.take(SNAPSHOT_MAGIC.len()).ok() != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        let mut registers = [0; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        let mut float_registers = [0.0; REGISTER_COUNT];
+        for register in float_registers.iter_mut() {
+            *register = f64::from_bits(reader.u64()?);
+        }
+        let pc = reader.u64()? as usize;
+        let remainder = reader.u32()? as i32;
+        let equal_flag = reader.u8()? != 0;
+        let overflow_flag = reader.u8()? != 0;
+        let overflow_mode = match reader.u8()? {
+            0 => OverflowMode::Trap,
+            1 => OverflowMode::Wrap,
+            2 => OverflowMode::Saturate,
+            _ => return Err(SnapshotError::Invalid),
+        };
+        let started = reader.u8()? != 0;
+        let sp = reader.u64()? as usize;
+        let bp = reader.u64()? as usize;
+        if sp > STACK_SIZE || bp > sp {
+            return Err(SnapshotError::Invalid);
         }
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+        let mut stack = vec![0; STACK_SIZE];
+        for slot in stack.iter_mut().take(sp) {
+            *slot = reader.u32()? as i32;
         }
+        let program = reader.vec()?;
+        let heap = reader.vec()?;
+        let (header, ro_data, imports) = if started {
+            let header = PieHeader::parse(&program).map_err(|_| SnapshotError::Invalid)?;
+            let ro_data = header.data(&program).to_vec();
+            let imports = self
+                .resolve_imports(&program, &header)
+                .map_err(|name| SnapshotError::UnknownHostFunction { name })?;
+            (Some(header), ro_data, imports)
+        } else {
+            (None, vec![], HashMap::new())
+        };
+
+        self.registers = registers;
+        self.float_registers = float_registers;
+        self.program = program;
+        self.header = header;
+        self.ro_data = ro_data;
+        self.imports = imports;
+        self.heap = heap;
+        self.stack = stack;
+        self.sp = sp;
+        self.bp = bp;
+        self.pc = pc;
+        self.instruction_pc = pc;
+        self.remainder = remainder;
+        self.equal_flag = equal_flag;
+        self.overflow_flag = overflow_flag;
+        self.overflow_mode = overflow_mode;
+        Ok(())
+    }
+
+    /// Address of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// The read-only data section loaded from the program
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
     }
 
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte);
     }
 
+    /// Replaces the program with `program` and clears everything the old one left behind:
+    /// registers, flags, stack, heap and header. Settings such as the overflow mode and
+    /// the registered host functions are kept, so the next `run` starts the new program
+    /// from its entry point.
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.header = None;
+        self.ro_data.clear();
+        self.imports.clear();
+        self.heap.clear();
+        self.registers = [0; REGISTER_COUNT];
+        self.float_registers = [0.0; REGISTER_COUNT];
+        self.stack = vec![0; STACK_SIZE];
+        self.sp = 0;
+        self.bp = 0;
+        self.pc = 0;
+        self.instruction_pc = 0;
+        self.remainder = 0;
+        self.equal_flag = false;
+        self.overflow_flag = false;
+    }
+
     pub fn add_bytes(&mut self, mut bytes: Vec<u8>) {
         self.program.append(&mut bytes);
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        if self.pc >= self.program.len() {
-            return true;
+    fn execute_instruction(&mut self) -> Result<ExitStatus, VmError> {
+        if self.pc == self.code_end() {
+            return Ok(ExitStatus::EndOfProgram);
+        }
+        // The header and read-only data are not code. `instruction_pc` is still the
+        // instruction that jumped here.
+        if self.pc < self.code_start() || self.pc > self.code_end() {
+            return Err(VmError::InvalidJumpTarget {
+                target: self.pc as i64,
+                pc: self.instruction_pc,
+            });
+        }
+        self.instruction_pc = self.pc;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(self.pc, Opcode::from(self.program[self.pc]));
+        }
+        if self.trace.is_some() {
+            return self.execute_traced();
+        }
+        self.execute_opcode()
+    }
+
+    /// Executes the instruction at `pc` and writes a line describing it to the trace sink:
+    /// its address, the instruction, and every register and flag it changed
+    fn execute_traced(&mut self) -> Result<ExitStatus, VmError> {
+        let end = (self.pc + INSTRUCTION_LENGTH).min(self.program.len());
+        let instruction = disassemble_instruction(&self.program[self.pc..end]);
+        let before = TraceState::capture(self);
+        let result = self.execute_opcode();
+        let mut line = format!("{:04}: {}", self.instruction_pc, instruction);
+        match &result {
+            Ok(_) => {
+                let changes = before.changes(&TraceState::capture(self));
+                if !changes.is_empty() {
+                    line.push_str(&format!(" ; {}", changes.join(" ")));
+                }
+            }
+            Err(e) => line.push_str(&format!(" ; error: {}", e)),
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            // A sink that fails to write should not stop the program
+            let _ = writeln!(trace, "{}", line);
         }
+        result
+    }
+
+    fn execute_opcode(&mut self) -> Result<ExitStatus, VmError> {
         match self.decode_opcode() {
             Opcode::LOAD => {
-                let target_register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
-                self.registers[target_register] = number as i32;
+                let target_register = self.next_register()?;
+                self.registers[target_register] = self.next_immediate()?;
+            }
+            Opcode::MOV => {
+                let destination = self.next_register()?;
+                self.registers[destination] = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+            }
+            Opcode::ADDI => {
+                let register = self.next_register()?;
+                let (value, immediate) = (self.registers[register], self.next_immediate()?);
+                self.registers[register] = self.overflowing(
+                    value.overflowing_add(immediate),
+                    value.saturating_add(immediate),
+                )?;
+            }
+            Opcode::SUBI => {
+                let register = self.next_register()?;
+                let (value, immediate) = (self.registers[register], self.next_immediate()?);
+                self.registers[register] = self.overflowing(
+                    value.overflowing_sub(immediate),
+                    value.saturating_sub(immediate),
+                )?;
+            }
+            Opcode::MULI => {
+                let register = self.next_register()?;
+                let (value, immediate) = (self.registers[register], self.next_immediate()?);
+                self.registers[register] = self.overflowing(
+                    value.overflowing_mul(immediate),
+                    value.saturating_mul(immediate),
+                )?;
+            }
+            Opcode::DIVI => {
+                let register = self.next_register()?;
+                let (value, immediate) = (self.registers[register], self.next_immediate()?);
+                if immediate == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+                self.registers[register] = self.overflowing(
+                    value.overflowing_div(immediate),
+                    value.saturating_div(immediate),
+                )?;
+                self.remainder = value.wrapping_rem(immediate);
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                let result = self.overflowing(
+                    register1.overflowing_add(register2),
+                    register1.saturating_add(register2),
+                )?;
+                self.registers[self.next_register()?] = result;
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                let result = self.overflowing(
+                    register1.overflowing_sub(register2),
+                    register1.saturating_sub(register2),
+                )?;
+                self.registers[self.next_register()?] = result;
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                let result = self.overflowing(
+                    register1.overflowing_mul(register2),
+                    register1.saturating_mul(register2),
+                )?;
+                self.registers[self.next_register()?] = result;
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as usize;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+                // i32::MIN / -1 is the only quotient that overflows
+                let result = self.overflowing(
+                    register1.overflowing_div(register2),
+                    register1.saturating_div(register2),
+                )?;
+                self.registers[self.next_register()?] = result;
+                self.remainder = register1.wrapping_rem(register2);
+            }
+            Opcode::MOD => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                if register2 == 0 {
+                    return Err(VmError::DivisionByZero {
+                        pc: self.instruction_pc,
+                    });
+                }
+                // Truncating, like DIV: -7 mod 2 is -1. i32::MIN mod -1 is 0.
+                self.registers[self.next_register()?] = register1.wrapping_rem(register2);
+            }
+            Opcode::MFR => {
+                self.registers[self.next_register()?] = self.remainder;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             Opcode::HLT => {
-                println!("HLT encoutered");
-                return true;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                return Ok(ExitStatus::Halted);
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
+                let target = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
                 self.pc = target as usize;
             }
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += value;
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.jump_relative(value as i64)?;
             }
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= value;
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.jump_relative(-(value as i64))?;
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 == register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
+            }
+            Opcode::EQI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value == self.next_immediate()?;
+            }
+            Opcode::NEQI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value != self.next_immediate()?;
+            }
+            Opcode::GTI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value > self.next_immediate()?;
+            }
+            Opcode::GTEI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value >= self.next_immediate()?;
+            }
+            Opcode::LTI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value < self.next_immediate()?;
+            }
+            Opcode::LTEI => {
+                let value = self.registers[self.next_register()?];
+                self.equal_flag = value <= self.next_immediate()?;
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 != register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 > register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::GTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 >= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 < register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 <= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::JMPE => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let target = self.registers[register];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if self.equal_flag {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::JMPNE => {
+                let target = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if !self.equal_flag {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::JMPFE => {
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
                 if self.equal_flag {
+                    self.jump_relative(value as i64)?;
+                }
+            }
+            Opcode::JMPBE => {
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if self.equal_flag {
+                    self.jump_relative(-(value as i64))?;
+                }
+            }
+            Opcode::JMPO => {
+                let register = self.next_register()?;
+                let target = self.registers[register];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if self.overflow_flag {
+                    self.pc = target as usize;
+                }
+            }
+            Opcode::JMPNO => {
+                let register = self.next_register()?;
+                let target = self.registers[register];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if !self.overflow_flag {
                     self.pc = target as usize;
-                } else {
-                    // TODO: fix the bits
                 }
             }
             Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
+                let new_end = self.heap.len() as i64 + bytes as i64;
+                if bytes < 0 || new_end as u64 > self.heap_limit as u64 {
+                    return Err(VmError::HeapOutOfBounds {
+                        address: new_end,
+                        pc: self.instruction_pc,
+                    });
+                }
+                let base = VM::heap_base(self.heap.len(), self.instruction_pc)?;
                 self.heap.resize(new_end as usize, 0);
-                // TODO: 1: check: Don't we need to read next 16 bits here?
+                self.registers[self.next_register()?] = base;
+                self.next_8_bits()?;
+            }
+            Opcode::FREE => {
+                // The heap is a bump allocator, so blocks are released from the end
+                let bytes = self.registers[self.next_register()?];
+                let new_end = self.heap.len() as i64 - bytes as i64;
+                if bytes < 0 || new_end < 0 {
+                    return Err(VmError::HeapOutOfBounds {
+                        address: new_end,
+                        pc: self.instruction_pc,
+                    });
+                }
+                self.heap.truncate(new_end as usize);
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::LOADB => {
+                let register = self.next_register()?;
+                let address = self.heap_address(1)?;
+                self.registers[register] = self.heap[address] as i32;
+            }
+            Opcode::STOREB => {
+                let value = self.registers[self.next_register()?];
+                let address = self.heap_address(1)?;
+                self.heap[address] = value as u8;
+            }
+            Opcode::LOADW => {
+                let register = self.next_register()?;
+                let address = self.heap_address(4)?;
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&self.heap[address..address + 4]);
+                self.registers[register] = i32::from_be_bytes(bytes);
+            }
+            Opcode::STOREW => {
+                let value = self.registers[self.next_register()?];
+                let address = self.heap_address(4)?;
+                self.heap[address..address + 4].copy_from_slice(&value.to_be_bytes());
             }
             Opcode::INC => {
-                let register_number = self.next_8_bits() as usize;
-                self.registers[register_number] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                let register_number = self.next_register()?;
+                let value = self.registers[register_number];
+                self.registers[register_number] =
+                    self.overflowing(value.overflowing_add(1), value.saturating_add(1))?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             Opcode::DEC => {
-                let register_number = self.next_8_bits() as usize;
-                self.registers[register_number] -= 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                let register_number = self.next_register()?;
+                let value = self.registers[register_number];
+                self.registers[register_number] =
+                    self.overflowing(value.overflowing_sub(1), value.saturating_sub(1))?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::PUSH => {
+                let value = self.registers[self.next_register()?];
+                self.push(value)?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::POP => {
+                let register = self.next_register()?;
+                self.registers[register] = self.pop()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::SYSCALL => {
+                let number = self.next_16_bits()?;
+                self.next_8_bits()?;
+                if number as usize >= self.host_functions.len() {
+                    return Err(VmError::InvalidSyscall {
+                        number,
+                        pc: self.instruction_pc,
+                    });
+                }
+                self.call_host_function(number as usize)?;
+            }
+            Opcode::CALLHOST => {
+                let index = self.next_16_bits()?;
+                self.next_8_bits()?;
+                match self.imports.get(&(index as u32)) {
+                    Some(number) => self.call_host_function(*number)?,
+                    None => {
+                        return Err(VmError::InvalidImport {
+                            index,
+                            pc: self.instruction_pc,
+                        })
+                    }
+                }
+            }
+            Opcode::CALL => {
+                let destination = self.next_16_bits()?;
+                self.next_8_bits()?;
+                let return_destination = self.pc as i32;
+                self.push(return_destination)?;
+                self.push(self.bp as i32)?;
+                self.bp = self.sp;
+                self.pc = destination as usize;
+            }
+            Opcode::RET => {
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.sp = self.bp;
+                let bp = self.pop()?;
+                let return_destination = self.pop()?;
+                // The saved frame pointer is ordinary stack memory the program may have
+                // overwritten, so it has to point into the stack below us
+                if bp as i64 > STACK_SIZE as i64 {
+                    return Err(VmError::StackOverflow {
+                        pc: self.instruction_pc,
+                    });
+                }
+                if bp < 0 || bp as usize > self.sp {
+                    return Err(VmError::StackUnderflow {
+                        pc: self.instruction_pc,
+                    });
+                }
+                self.bp = bp as usize;
+                self.pc = return_destination as usize;
+            }
+            Opcode::LUI => {
+                // Replaces the upper half of the register and keeps the lower half, so
+                // LOAD followed by LUI builds any 32-bit value
+                let register = self.next_register()?;
+                let upper = self.next_16_bits()? as i16 as i32;
+                self.registers[register] = (upper << 16) | (self.registers[register] & 0xFFFF);
+            }
+            Opcode::LOADF64 => {
+                let register = self.next_register()?;
+                let offset = self.next_16_bits()? as usize;
+                let constant =
+                    self.ro_data
+                        .get(offset..offset + 8)
+                        .ok_or(VmError::ConstantOutOfBounds {
+                            offset,
+                            pc: self.instruction_pc,
+                        })?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(constant);
+                self.float_registers[register] = f64::from_be_bytes(bytes);
+            }
+            Opcode::PRTI => {
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.write_output(value.to_string().as_bytes())?;
+            }
+            Opcode::PRTS => {
+                let offset = self.next_16_bits()? as usize;
+                self.next_8_bits()?;
+                let data = self
+                    .ro_data
+                    .get(offset..)
+                    .ok_or(VmError::ConstantOutOfBounds {
+                        offset,
+                        pc: self.instruction_pc,
+                    })?;
+                // A string missing its terminator runs to the end of the data
+                let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                let text = data[..end].to_vec();
+                self.write_output(&text)?;
+            }
+            Opcode::PRTC => {
+                let value = self.registers[self.next_register()?];
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let c = char::from_u32(value as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+                self.write_output(c.to_string().as_bytes())?;
+            }
+            Opcode::ADDF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 + register2;
+            }
+            Opcode::SUBF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 - register2;
+            }
+            Opcode::MULF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 * register2;
+            }
+            Opcode::DIVF64 => {
+                // Dividing by zero gives an infinity or NaN rather than a fault
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 / register2;
+            }
+            Opcode::EQF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 == register2;
+                self.next_8_bits()?;
+            }
+            Opcode::NEQF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 != register2;
+                self.next_8_bits()?;
+            }
+            Opcode::GTF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 > register2;
+                self.next_8_bits()?;
+            }
+            Opcode::GTEF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 >= register2;
+                self.next_8_bits()?;
+            }
+            Opcode::LTF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 < register2;
+                self.next_8_bits()?;
+            }
+            Opcode::LTEF64 => {
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.equal_flag = register1 <= register2;
+                self.next_8_bits()?;
+            }
+            Opcode::ITOF => {
+                let target = self.next_register()?;
+                self.float_registers[target] = self.registers[self.next_register()?] as f64;
+                self.next_8_bits()?;
+            }
+            Opcode::FTOI => {
+                // Truncates towards zero, saturating at the i32 limits; NaN becomes 0
+                let target = self.next_register()?;
+                self.registers[target] = self.float_registers[self.next_register()?] as i32;
+                self.next_8_bits()?;
+            }
+            Opcode::AND => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 & register2;
+            }
+            Opcode::OR => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 | register2;
+            }
+            Opcode::XOR => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 ^ register2;
+            }
+            Opcode::NOT => {
+                let register1 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = !register1;
+                self.next_8_bits()?;
+            }
+            // Shift amounts only use their lowest five bits, so shifting by 32 is a no-op
+            Opcode::SHL => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_shl(register2 as u32);
+            }
+            Opcode::SHR => {
+                let register1 = self.registers[self.next_register()?] as u32;
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] =
+                    register1.wrapping_shr(register2 as u32) as i32;
+            }
+            Opcode::SAR => {
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_shr(register2 as u32);
             }
             Opcode::IGL => {
-                println!("Illegal instruction encountered");
-                return true;
+                return Err(VmError::IllegalOpcode {
+                    opcode: self.program[self.instruction_pc],
+                    pc: self.instruction_pc,
+                });
             }
         }
-        false
+        Ok(ExitStatus::Running)
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -178,58 +1263,242 @@ impl VM {
         opcode
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        let result = *self
+            .program
+            .get(self.pc)
+            .ok_or(VmError::TruncatedInstruction {
+                pc: self.instruction_pc,
+            })?;
         self.pc += 1;
-        result
+        Ok(result)
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let high = self.next_8_bits()? as u16;
+        let low = self.next_8_bits()? as u16;
+        Ok((high << 8) | low)
+    }
+
+    /// Reads a 16-bit immediate operand, which is signed, so `load $0 #-1` loads -1
+    fn next_immediate(&mut self) -> Result<i32, VmError> {
+        Ok(self.next_16_bits()? as i16 as i32)
     }
 
-    fn verify_header(&self) -> bool {
-        if self.program[0..4] != assembler::PIE_HEADER_PREFIX {
-            return false;
+    /// Picks the result of an integer operation according to the overflow mode, given the
+    /// wrapped result with whether it overflowed, and the saturated result
+    fn overflowing(&mut self, wrapped: (i32, bool), saturated: i32) -> Result<i32, VmError> {
+        let (wrapped, overflowed) = wrapped;
+        self.overflow_flag = overflowed;
+        if !overflowed {
+            return Ok(wrapped);
+        }
+        match self.overflow_mode {
+            OverflowMode::Trap => Err(VmError::ArithmeticOverflow {
+                pc: self.instruction_pc,
+            }),
+            OverflowMode::Wrap => Ok(wrapped),
+            OverflowMode::Saturate => Ok(saturated),
         }
-        true
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+    /// Reads the base register and offset operands of a heap load or store, checking that
+    /// `len` bytes at the address they add up to are inside the heap
+    fn heap_address(&mut self, len: usize) -> Result<usize, VmError> {
+        let base = self.registers[self.next_register()?];
+        let address = base as i64 + self.next_8_bits()? as i64;
+        if address < 0 || address + len as i64 > self.heap.len() as i64 {
+            return Err(VmError::HeapOutOfBounds {
+                address,
+                pc: self.instruction_pc,
+            });
+        }
+        Ok(address as usize)
+    }
 
-    use super::*;
+    /// The address a block allocated at the end of a heap `len` bytes long starts at.
+    /// Registers can only hold addresses up to i32::MAX.
+    fn heap_base(len: usize, pc: usize) -> Result<i32, VmError> {
+        i32::try_from(len).map_err(|_| VmError::HeapOutOfBounds {
+            address: len as i64,
+            pc,
+        })
+    }
 
-    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
-        let mut prepension = vec![];
-        for byte in PIE_HEADER_PREFIX.into_iter() {
-            prepension.push(byte.clone());
+    /// Reads a register operand, checking that the register exists
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.registers.len() {
+            return Err(VmError::RegisterOutOfRange {
+                register,
+                pc: self.instruction_pc,
+            });
         }
-        while prepension.len() <= PIE_HEADER_LENGTH {
-            prepension.push(0);
+        Ok(register as usize)
+    }
+
+    fn jump_relative(&mut self, offset: i64) -> Result<(), VmError> {
+        let target = self.pc as i64 + offset;
+        if target < 0 {
+            return Err(VmError::InvalidJumpTarget {
+                target,
+                pc: self.instruction_pc,
+            });
         }
-        prepension.append(&mut b);
-        prepension
+        self.pc = target as usize;
+        Ok(())
     }
 
-    #[test]
-    fn test_create_vm() {
-        let test_vm = VM::new();
-        assert_eq!(test_vm.registers[0], 0)
+    fn push(&mut self, value: i32) -> Result<(), VmError> {
+        if self.sp >= self.stack.len() {
+            return Err(VmError::StackOverflow {
+                pc: self.instruction_pc,
+            });
+        }
+        self.stack[self.sp] = value;
+        self.sp += 1;
+        Ok(())
     }
 
-    #[test]
-    fn test_add_byte() {
-        let mut test_vm = VM::new();
-        assert_eq!(test_vm.program.len(), 0);
-        test_vm.add_byte(10);
-        assert_eq!(test_vm.program.len(), 1);
-        test_vm.add_byte(0x0C);
-        assert_eq!(test_vm.program.len(), 2);
+    fn pop(&mut self) -> Result<i32, VmError> {
+        if self.sp == 0 {
+            return Err(VmError::StackUnderflow {
+                pc: self.instruction_pc,
+            });
+        }
+        self.sp -= 1;
+        Ok(self.stack[self.sp])
+    }
+
+    /// Checks the PIE header, copies the read-only data section out of the program and
+    /// moves the program counter to the entry point
+    fn load_header(&mut self) -> Result<(), VmError> {
+        let header = PieHeader::parse(&self.program)?;
+        self.ro_data = header.data(&self.program).to_vec();
+        self.imports = self
+            .resolve_imports(&self.program, &header)
+            .map_err(|name| VmError::UnknownHostFunction { name })?;
+        self.pc = header.entry_point as usize;
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Finds the registered host function each import of `program` names, or returns the
+    /// name of one that is not registered
+    fn resolve_imports(
+        &self,
+        program: &[u8],
+        header: &PieHeader,
+    ) -> Result<HashMap<u32, usize>, String> {
+        let mut imports = HashMap::new();
+        let symbols = header.symbols(program).and_then(SymbolTable::from_bytes);
+        for symbol in symbols.iter().flat_map(|s| s.symbols()) {
+            if *symbol.symbol_type() != SymbolType::Import {
+                continue;
+            }
+            match self
+                .host_functions
+                .iter()
+                .position(|(name, _)| name == symbol.name())
+            {
+                Some(number) => imports.insert(symbol.offset(), number),
+                None => return Err(symbol.name().to_string()),
+            };
+        }
+        Ok(imports)
+    }
+
+    fn write_output(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.output
+            .write_all(bytes)
+            .map_err(|e| VmError::OutputFailed {
+                message: e.to_string(),
+                pc: self.instruction_pc,
+            })
+    }
+
+    fn call_host_function(&mut self, number: usize) -> Result<(), VmError> {
+        let (name, function) = &mut self.host_functions[number];
+        let mut context = HostContext {
+            registers: &mut self.registers,
+            float_registers: &mut self.float_registers,
+            heap: &mut self.heap,
+            ro_data: &self.ro_data,
+        };
+        function(&mut context).map_err(|message| VmError::HostFunctionFailed {
+            name: name.clone(),
+            message,
+            pc: self.instruction_pc,
+        })
+    }
+
+    /// Start of the code section. Programs without a header, such as the ones the REPL
+    /// builds, are all code.
+    fn code_start(&self) -> usize {
+        match &self.header {
+            Some(header) => header.code_offset as usize,
+            None => 0,
+        }
+    }
+
+    /// End of the code, which is never past the end of `program`, in case that was
+    /// replaced after the header was read
+    pub fn code_end(&self) -> usize {
+        match &self.header {
+            Some(header) => header.code_end().min(self.program.len()),
+            None => self.program.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+
+    use super::*;
+
+    /// A trace sink the test can still read after handing it to the VM
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
+        let mut prepension = PieHeader::new(0, b.len() as u32).to_bytes();
+        prepension.append(&mut b);
+        prepension
+    }
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0)
+    }
+
+    #[test]
+    fn test_vm_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+    }
+
+    #[test]
+    fn test_add_byte() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.program.len(), 0);
+        test_vm.add_byte(10);
+        assert_eq!(test_vm.program.len(), 1);
+        test_vm.add_byte(0x0C);
+        assert_eq!(test_vm.program.len(), 2);
     }
 
     #[test]
@@ -237,18 +1506,35 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![5, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
     }
 
     #[test]
     fn test_opcode_load() {
         let mut test_vm = VM::new();
         test_vm.program = vec![0, 0, 1 /* 2^8 = 256*/, 244];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 500 /* 256 + 244 */);
     }
 
+    #[test]
+    fn test_opcode_lui() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0x86, 0xA0, 24, 0, 0, 1];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 100_000);
+    }
+
+    #[test]
+    fn test_opcode_load_negative() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 255, 254];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], -2);
+    }
+
     #[test]
     fn test_opcode_add() {
         let mut test_vm = VM::new();
@@ -266,11 +1552,11 @@ mod tests {
             1,   /* and register 1: 255 */
             2,   /* store in register 2*/
         ];
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[0], 268);
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[1], 255);
-        test_vm.run_once(); // ADD
+        test_vm.run_once().unwrap(); // ADD
         assert_eq!(test_vm.registers[2], 523);
     }
 
@@ -291,11 +1577,11 @@ mod tests {
             1,   /* and register 1: 255 */
             2,   /* store in register 2*/
         ];
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[0], 268);
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[1], 255);
-        test_vm.run_once(); // SUB
+        test_vm.run_once().unwrap(); // SUB
         assert_eq!(test_vm.registers[2], 13);
     }
 
@@ -317,12 +1603,12 @@ mod tests {
             2,   /* store in register 2*/
         ];
         test_vm.program = prepend_header(test_vm.program);
-        test_vm.pc += 65;
-        test_vm.run_once(); // LOAD
+        test_vm.pc += PIE_HEADER_LENGTH;
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[0], 268);
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[1], 255);
-        test_vm.run_once(); // MUL
+        test_vm.run_once().unwrap(); // MUL
         assert_eq!(test_vm.registers[2], 68340);
     }
 
@@ -343,23 +1629,57 @@ mod tests {
             1,   /* and register 1: 255 */
             2,   /* store in register 2*/
         ];
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[0], 268);
         assert_eq!(test_vm.remainder, 0);
-        test_vm.run_once(); // LOAD
+        test_vm.run_once().unwrap(); // LOAD
         assert_eq!(test_vm.registers[1], 255);
         assert_eq!(test_vm.remainder, 0);
-        test_vm.run_once(); // DIV
+        test_vm.run_once().unwrap(); // DIV
         assert_eq!(test_vm.registers[2], 1);
         assert_eq!(test_vm.remainder, 13);
     }
 
+    #[test]
+    fn test_opcode_div_negative_remainder() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![4 /* DIV */, 0, 1, 2, 48 /* MFR */, 3, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.registers[3], -1);
+    }
+
+    #[test]
+    fn test_opcode_mod() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.registers[1] = -2;
+        test_vm.registers[2] = i32::MIN;
+        test_vm.registers[3] = -1;
+        test_vm.program = vec![47 /* MOD */, 0, 1, 4, 47 /* MOD */, 2, 3, 5];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[5], 0);
+    }
+
+    #[test]
+    fn test_opcode_mod_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = vec![47 /* MOD */, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
     #[test]
     fn test_opcode_jmp() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -373,33 +1693,171 @@ mod tests {
             0, /* pad */
             0, /* pad */
         ];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(
             test_vm.pc,
-            4 /* 1. Read JMPF, 2. Read 0, then + 2 = 4 */
+            6 /* 1. Read the whole 4-byte JMPF, then + 2 = 6 */
         );
     }
 
     #[test]
     fn test_opcode_jmpb() {
         let mut test_vm = VM::new();
-        test_vm.registers[1] = 6;
+        test_vm.registers[1] = 8;
         test_vm.program = vec![0, 0, 0, 10, 8, 1, 0, 0];
-        test_vm.run_once(); // LOAD: pc += 4
-        test_vm.run_once(); // Read JMPB and target (pc += 2), then JMPB to register1: 6 (pc -= 6)
+        test_vm.run_once().unwrap(); // LOAD: pc += 4
+        test_vm.run_once().unwrap(); // Read JMPB (pc += 4), then JMPB by register1: 8 (pc -= 8)
         assert_eq!(test_vm.pc, 0);
     }
 
+    #[test]
+    fn test_opcode_and() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![38 /* AND */, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1000);
+    }
+
+    #[test]
+    fn test_opcode_or() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![39 /* OR */, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1110);
+    }
+
+    #[test]
+    fn test_opcode_xor() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![40 /* XOR */, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0b0110);
+    }
+
+    #[test]
+    fn test_opcode_not() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.program = vec![41 /* NOT */, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], -13);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_shl() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![42 /* SHL */, 0, 1, 2, 42 /* SHL */, 0, 3, 4];
+        test_vm.registers[3] = 33;
+        test_vm.run_once().unwrap(); // 3 << 4
+        assert_eq!(test_vm.registers[2], 48);
+        test_vm.run_once().unwrap(); // 3 << 33, i.e. 3 << 1
+        assert_eq!(test_vm.registers[4], 6);
+    }
+
+    #[test]
+    fn test_opcode_shr() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![43 /* SHR */, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0x3FFF_FFFC);
+    }
+
+    #[test]
+    fn test_opcode_sar() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![44 /* SAR */, 0, 1, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], -4);
+    }
+
+    #[test]
+    fn test_overflow_modes() {
+        let program = vec![1 /* ADD */, 0, 1, 2, 3 /* MUL */, 0, 1, 3];
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 2;
+        test_vm.program = program.clone();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN + 1);
+        assert!(test_vm.overflow_flag);
+
+        test_vm.overflow_mode = OverflowMode::Saturate;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], i32::MAX);
+        assert!(test_vm.overflow_flag);
+
+        test_vm.overflow_mode = OverflowMode::Trap;
+        test_vm.pc = 0;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ArithmeticOverflow { pc: 0 })
+        );
+        test_vm.registers[0] = 1;
+        test_vm.pc = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 3);
+        assert!(!test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_overflow_inc_dec_div() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_mode = OverflowMode::Saturate;
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.registers[2] = i32::MAX;
+        test_vm.program = vec![
+            19, /* DEC */ 0, 0, 0, 18, /* INC */ 2, 0, 0, 4, /* DIV */ 0, 1, 3,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], i32::MIN);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[3], i32::MAX);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_opcode_jmpo_jmpno() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        test_vm.overflow_flag = true;
+        test_vm.program = vec![45 /* JMPO */, 0, 0, 0, 46 /* JMPNO */, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 12);
+        test_vm.pc = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        test_vm.overflow_flag = false;
+        test_vm.pc = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 12);
+    }
+
     #[test]
     fn test_opcode_eq() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![9 /* EQ */, 0, 1, 0, 9 /* EQ */, 0, 1, 0];
-        test_vm.run_once(); // 10 == 10
+        test_vm.run_once().unwrap(); // 10 == 10
         assert_eq!(test_vm.equal_flag, 10 == 10);
         test_vm.registers[1] = 20;
-        test_vm.run_once(); // 10 == 20
+        test_vm.run_once().unwrap(); // 10 == 20
         assert_eq!(test_vm.equal_flag, 10 == 20);
     }
 
@@ -409,10 +1867,10 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![10 /* NEQ */, 0, 1, 0, 10 /* NEQ */, 0, 1, 0];
-        test_vm.run_once(); // 10 != 10
+        test_vm.run_once().unwrap(); // 10 != 10
         assert_eq!(test_vm.equal_flag, 10 != 10);
         test_vm.registers[1] = 20;
-        test_vm.run_once(); // 10 != 20
+        test_vm.run_once().unwrap(); // 10 != 20
         assert_eq!(test_vm.equal_flag, 10 != 20);
     }
 
@@ -422,10 +1880,10 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![11 /* GT */, 0, 1, 0, 11 /* GT */, 0, 1, 0];
-        test_vm.run_once(); // 10 > 10
+        test_vm.run_once().unwrap(); // 10 > 10
         assert_eq!(test_vm.equal_flag, 10 > 10);
         test_vm.registers[0] = 99;
-        test_vm.run_once(); // 99 > 10
+        test_vm.run_once().unwrap(); // 99 > 10
         assert_eq!(test_vm.equal_flag, 99 > 10);
     }
 
@@ -435,13 +1893,13 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0, 12, 0, 1, 0];
-        test_vm.run_once(); // 10 >= 10
+        test_vm.run_once().unwrap(); // 10 >= 10
         assert_eq!(test_vm.equal_flag, 10 >= 10);
         test_vm.registers[0] = 99;
-        test_vm.run_once(); // 99 >= 10
+        test_vm.run_once().unwrap(); // 99 >= 10
         assert_eq!(test_vm.equal_flag, 99 >= 10);
         test_vm.registers[0] = 3;
-        test_vm.run_once(); // 3 >= 10
+        test_vm.run_once().unwrap(); // 3 >= 10
         assert_eq!(test_vm.equal_flag, 3 >= 10);
     }
 
@@ -451,10 +1909,10 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![13 /* LT */, 0, 1, 0, 13 /* LT */, 0, 1, 0];
-        test_vm.run_once(); // 10 < 10
+        test_vm.run_once().unwrap(); // 10 < 10
         assert_eq!(test_vm.equal_flag, 10 < 10);
         test_vm.registers[0] = 3;
-        test_vm.run_once(); // 3 < 10
+        test_vm.run_once().unwrap(); // 3 < 10
         assert_eq!(test_vm.equal_flag, 3 < 10);
     }
 
@@ -464,13 +1922,13 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0, 14, 0, 1, 0];
-        test_vm.run_once(); // 10 <= 10
+        test_vm.run_once().unwrap(); // 10 <= 10
         assert_eq!(test_vm.equal_flag, 10 <= 10);
         test_vm.registers[1] = 99;
-        test_vm.run_once(); // 10 <= 99
+        test_vm.run_once().unwrap(); // 10 <= 99
         assert_eq!(test_vm.equal_flag, 10 <= 99);
         test_vm.registers[1] = 3;
-        test_vm.run_once(); // 10 <= 3
+        test_vm.run_once().unwrap(); // 10 <= 3
         assert_eq!(test_vm.equal_flag, 10 <= 3);
     }
 
@@ -480,7 +1938,7 @@ mod tests {
         test_vm.registers[0] = 7;
         test_vm.equal_flag = true;
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 7);
     }
     #[test]
@@ -489,9 +1947,42 @@ mod tests {
         test_vm.registers[0] = 7;
         test_vm.equal_flag = false;
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.pc, 2);
-        // TODO: fix the bits assert_eq!(test_vm.pc, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmpne() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 12;
+        test_vm.program = vec![54 /* JMPNE */, 0, 0, 0, 54 /* JMPNE */, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 12);
+        test_vm.equal_flag = true;
+        test_vm.pc = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_jmpfe_jmpbe() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 4;
+        test_vm.equal_flag = true;
+        test_vm.program = vec![
+            55, /* JMPFE */ 0, 0, 0, 5, /* HLT */ 0, 0, 0, 56, /* JMPBE */ 0, 0, 0,
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        test_vm.equal_flag = false;
+        test_vm.pc = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+        test_vm.pc = 8;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 12);
     }
 
     #[test]
@@ -499,9 +1990,106 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1024;
         test_vm.program = vec![17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
-        // TODO: 2: ref TODO 1
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_aloc_returns_base() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![17 /* ALOC */, 0, 1, 0, 17 /* ALOC */, 0, 2, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1..3], [0, 16]);
+        assert_eq!(test_vm.heap.len(), 32);
+    }
+
+    #[test]
+    fn test_opcode_aloc_heap_limit() {
+        let mut test_vm = VM::new();
+        test_vm.heap_limit = 24;
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![17 /* ALOC */, 0, 1, 0, 17 /* ALOC */, 0, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { address: 32, pc: 4 })
+        );
+        assert_eq!(test_vm.heap.len(), 16);
+    }
+
+    #[test]
+    fn test_opcode_aloc_base_out_of_range() {
+        let len = i32::MAX as usize;
+        assert_eq!(VM::heap_base(len, 0), Ok(i32::MAX));
+        assert_eq!(
+            VM::heap_base(len + 1, 8),
+            Err(VmError::HeapOutOfBounds {
+                address: len as i64 + 1,
+                pc: 8
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_aloc_negative_size() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = -4;
+        test_vm.program = vec![17 /* ALOC */, 0, 1, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { address: 4, pc: 0 })
+        );
+        assert_eq!(test_vm.heap.len(), 8);
+    }
+
+    #[test]
+    fn test_opcode_free() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 32];
+        test_vm.registers[0] = 16;
+        test_vm.registers[1] = 17;
+        test_vm.program = vec![53 /* FREE */, 0, 0, 0, 53 /* FREE */, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 16);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { address: -1, pc: 4 })
+        );
+    }
+
+    #[test]
+    fn test_heap_loads_and_stores() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble(
+                "load $0 #8\naloc $0 $1\nload $2 #-2\nstorew $2 $1 #4\nloadw $3 $1 #4\n\
+                 storeb $2 $1 #1\nloadb $4 $1 #1\nloadb $5 $1 #7\nhlt\n",
+            )
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.heap, vec![0, 0xFE, 0, 0, 0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(test_vm.registers[3..6], [-2, 0xFE, 0xFE]);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 9;
+        test_vm.program = vec![51 /* LOADW */, 1, 0, 3, 50 /* STOREB */, 1, 0, 5];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { address: 5, pc: 0 })
+        );
+        test_vm.pc = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap[7], 9);
     }
 
     #[test]
@@ -509,7 +2097,7 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 4;
         test_vm.program = vec![18, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 5);
     }
 
@@ -518,7 +2106,7 @@ mod tests {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 4;
         test_vm.program = vec![19, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[0], 3);
     }
 
@@ -527,7 +2115,655 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run_once();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::IllegalOpcode { opcode: 200, pc: 0 })
+        );
         assert_eq!(test_vm.pc, 1);
     }
+
+    #[test]
+    fn test_run_halts() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![5, 0, 0, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 4);
+    }
+
+    #[test]
+    fn test_run_assembled_program() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm.assemble("load $0 #7\nhlt\n").unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_run_wide_loads() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 #100000\nload $1 #-100000\nload $2 #0xFFFFFFFF\nhlt\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0..3], [100_000, -100_000, -1]);
+    }
+
+    #[test]
+    fn test_mov_and_immediate_arithmetic() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble(
+                "load $0 #7\nmov $1 $0\nadd $1 #10\nsub $1 #-3\nmul $1 #2\ndiv $1 #-6\n\
+                 mov $2 $1\nmod $1 $0 $3\nhlt\n",
+            )
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0..4], [7, -6, -6, -6]);
+        assert_eq!(test_vm.remainder, 4);
+    }
+
+    #[test]
+    fn test_immediate_comparisons() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -5;
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("eq $0 #-5\nneq $0 #-5\ngt $0 #-6\ngte $0 #-4\nlt $0 #0\nlte $0 #-6\n")
+            .unwrap();
+        test_vm.add_bytes(program);
+        test_vm.load_header().unwrap();
+        let mut flags = vec![];
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+            flags.push(test_vm.equal_flag);
+        }
+        assert_eq!(flags, [true, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn test_opcode_divi_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![61 /* DIVI */, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_run_with_budget() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $1 @loop\nloop: inc $0\njmp $1\n")
+            .unwrap();
+        test_vm.costs = CostTable::uniform(1);
+        assert_eq!(test_vm.run_with_budget(21), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 10);
+        assert_eq!(test_vm.run_with_budget(4), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 12);
+    }
+
+    #[test]
+    fn test_run_with_budget_costs() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm.assemble("mul $0 $0 $0\ninc $1\nhlt\n").unwrap();
+        assert_eq!(test_vm.run_with_budget(3), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.pc(), 64);
+        assert_eq!(test_vm.run_with_budget(5), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.registers[1], 1);
+        assert_eq!(test_vm.run_with_budget(1), Ok(ExitStatus::Halted));
+    }
+
+    #[test]
+    fn test_run_breakpoints() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm.assemble("inc $0\nloop: inc $1\ndec $0\nhlt\n").unwrap();
+        test_vm.breakpoints.insert(68);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Breakpoint));
+        assert_eq!((test_vm.pc(), test_vm.registers[1]), (68, 0));
+        // Running again carries on past the breakpoint
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0..2], [0, 1]);
+    }
+
+    #[test]
+    fn test_print() {
+        let mut test_vm = VM::new();
+        let buffer = SharedBuffer::default();
+        test_vm.output = Box::new(buffer.clone());
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble(
+                ".data\ngreeting: .asciiz 'Hello'\nend: .asciiz '!'\n.code\nload $0 #-42\n\
+                 load $1 #32\nload $2 #10\nprts @greeting\nprtc $1\nprti $0\nprts @end\n\
+                 prtc $2\nhlt\n",
+            )
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"Hello -42!\n");
+    }
+
+    #[test]
+    fn test_prts_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.output = Box::new(SharedBuffer::default());
+        test_vm.program = vec![71, 0, 3, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ConstantOutOfBounds { offset: 3, pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut test_vm = VM::new();
+        let buffer = SharedBuffer::default();
+        test_vm.trace = Some(Box::new(buffer.clone()));
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 #3\nloadf64 $f1 #0.5\neq $0 $1\npush $0\ndiv $0 $1 $2\n")
+            .unwrap();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DivisionByZero {
+                pc: PIE_HEADER_LENGTH + 8 + 16
+            })
+        );
+        let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "0072: load $0 #3 ; $0=3\n0076: loadf64 $f1 #0 ; $f1=0.5\n0080: eq $0 $1\n\
+             0084: push $0 ; sp=1\n0088: div $0 $1 $2 ; error: division by zero at 88\n"
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut test_vm = VM::new();
+        test_vm.profile = Some(Profile::new());
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 #3\nloop: dec $0\neq $0 $1\njmpfe $3\njmpb $2\nhlt\n")
+            .unwrap();
+        test_vm.registers[2] = 16;
+        test_vm.registers[3] = 4;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        let profile = test_vm.profile.unwrap();
+        assert_eq!(profile.instructions, 1 + 3 * 3 + 2 + 1);
+        assert_eq!(profile.address_counts()[0], (68, 3));
+        assert_eq!(profile.opcode_counts()[0], (Opcode::EQ, 3));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data\nhalf: .f64 #0.5\n.code\nload $0 #3\nload $1 #8\nload $3 @loop\n\
+                 aloc $1 $2\nloop: push $0\nloadf64 $f0 @half\nstoreb $0 $2 #7\ndec $0\n\
+                 eq $0 $4\njmpne $3\nhlt\n",
+            )
+            .unwrap();
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        test_vm.overflow_mode = OverflowMode::Saturate;
+        test_vm.costs = CostTable::uniform(1);
+        assert_eq!(test_vm.run_with_budget(9), Ok(ExitStatus::OutOfFuel));
+
+        let snapshot = test_vm.snapshot();
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.pc(), test_vm.pc());
+        assert_eq!(restored.registers, test_vm.registers);
+        assert_eq!(restored.float_registers[0], 0.5);
+        assert_eq!(restored.heap, test_vm.heap);
+        assert_eq!(restored.stack[..1], [3]);
+        assert_eq!(restored.overflow_mode, OverflowMode::Saturate);
+        assert_eq!(restored.ro_data(), test_vm.ro_data());
+
+        // Both carry on to the same end
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(restored.run(), Ok(ExitStatus::Halted));
+        assert_eq!(restored.snapshot(), test_vm.snapshot());
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 9;
+        let mut snapshot = test_vm.snapshot();
+        assert_eq!(
+            test_vm.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        assert_eq!(test_vm.restore(b"PIE\0"), Err(SnapshotError::BadMagic));
+        snapshot[5] = 9;
+        assert_eq!(
+            test_vm.restore(&snapshot),
+            Err(SnapshotError::UnsupportedVersion { version: 9 })
+        );
+        assert_eq!(test_vm.registers[0], 9);
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let mut test_vm = VM::new();
+        let double = test_vm.register_host_function("double", |context| {
+            context.registers[0] *= 2;
+            Ok(())
+        });
+        test_vm.register_host_function("poke", |context| {
+            let value = context.registers[0] as u8;
+            context.heap.push(value);
+            Ok(())
+        });
+        assert_eq!(double, 0);
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 #5\nsyscall #0\nsyscall 'poke'\nsyscall 'double'\nhlt\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 20);
+        assert_eq!(test_vm.heap, vec![10]);
+    }
+
+    #[test]
+    fn test_restore_corrupted_frame() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            22, 0, 8, 0, /* CALL 8 */
+            5, 0, 0, 0, /* HLT */
+            23, 0, 0, 0, /* RET */
+        ];
+        test_vm.run_once().unwrap();
+        let mut snapshot = test_vm.snapshot();
+        // The saved frame pointer is the last stack slot, followed by the length-prefixed
+        // 12-byte program and the empty heap
+        let slot = snapshot.len() - 8 - 12 - 8 - 4;
+        snapshot[slot..slot + 4].copy_from_slice(&i32::MAX.to_be_bytes());
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.run_once(), Err(VmError::StackOverflow { pc: 8 }));
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut test_vm = VM::new();
+        test_vm.register_host_function("inc", |context| {
+            context.registers[1] += 1;
+            Ok(())
+        });
+        let first = Assembler::new()
+            .assemble(".data\nn: .word #1\n.code\nload $0 #9\npush $0\nsyscall 'inc'\nhlt\n")
+            .unwrap();
+        test_vm.load_program(first);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0..2], [9, 1]);
+
+        let second = Assembler::new()
+            .assemble("nop\nstart: syscall 'inc'\nhlt\n.entry @start\n")
+            .unwrap();
+        test_vm.load_program(second);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0..2], [0, 1]);
+        assert_eq!(test_vm.sp, 0);
+        assert!(test_vm.ro_data().is_empty());
+        assert_eq!(test_vm.pc(), PIE_HEADER_LENGTH + 12);
+    }
+
+    #[test]
+    fn test_host_function_errors() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("syscall 'missing'\nhlt\n").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnknownHostFunction {
+                name: "missing".to_string()
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.register_host_function("fail", |_| Err("no luck".to_string()));
+        test_vm.program = Assembler::new().assemble("syscall 'fail'\nhlt\n").unwrap();
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HostFunctionFailed {
+                name: "fail".to_string(),
+                message: "no luck".to_string(),
+                pc: 64
+            })
+        );
+
+        test_vm.load_program(Assembler::new().assemble("syscall #1\nhlt\n").unwrap());
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidSyscall { number: 1, pc: 64 })
+        );
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble(
+                "loadf64 $f0 #1.5\nloadf64 $f1 #-2.25\naddf64 $f0 $f1 $f2\nsubf64 $f0 $f1 $f3\n\
+                 mulf64 $f0 $f1 $f4\ndivf64 $f1 $f0 $f5\nloadf64 $f6 #1.5\nhlt\n",
+            )
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(
+            test_vm.float_registers[0..7],
+            [1.5, -2.25, -0.75, 3.75, -3.375, -1.5, 1.5]
+        );
+        assert_eq!(test_vm.ro_data().len(), 16);
+    }
+
+    #[test]
+    fn test_float_comparisons() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.5;
+        for (opcode, expected) in &[
+            (Opcode::EQF64, false),
+            (Opcode::NEQF64, true),
+            (Opcode::GTF64, false),
+            (Opcode::GTEF64, false),
+            (Opcode::LTF64, true),
+            (Opcode::LTEF64, true),
+        ] {
+            test_vm.program = vec![u8::from(*opcode), 0, 1, 0];
+            test_vm.pc = 0;
+            test_vm.run_once().unwrap();
+            assert_eq!(test_vm.equal_flag, *expected, "{}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_float_conversions() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[1] = 2.9;
+        test_vm.float_registers[2] = f64::NAN;
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("itof $f0 $0\nftoi $1 $f1\nftoi $2 $f2\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.float_registers[0], -7.0);
+        assert_eq!(test_vm.registers[1], 2);
+        assert_eq!(test_vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_loadf64_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![u8::from(Opcode::LOADF64), 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::ConstantOutOfBounds { offset: 0, pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_load_label_and_jump() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 @loop\nloop: load $1 #1\njmp $0\n")
+            .unwrap();
+        test_vm.pc = PIE_HEADER_LENGTH;
+        test_vm.run_once().unwrap(); // load $0 @loop
+        test_vm.run_once().unwrap(); // lui $0 #0
+        test_vm.run_once().unwrap(); // load $1 #1
+        test_vm.run_once().unwrap(); // jmp $0
+        assert_eq!(test_vm.registers[0], 72);
+        assert_eq!(test_vm.pc, 72);
+    }
+
+    #[test]
+    fn test_run_bad_header() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![5, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::BadHeader));
+    }
+
+    #[test]
+    fn test_run_end_of_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![];
+        assert_eq!(test_vm.run_once(), Ok(ExitStatus::EndOfProgram));
+    }
+
+    #[test]
+    fn test_run_jump_outside_code() {
+        for (target, jump, source) in &[
+            (0, 64, "loop: jmp $0\n"),
+            (200, 68, "load $0 #200\njmp $0\n"),
+        ] {
+            let mut test_vm = VM::new();
+            test_vm.load_program(Assembler::new().assemble(source).unwrap());
+            assert_eq!(
+                test_vm.run(),
+                Err(VmError::InvalidJumpTarget {
+                    target: *target,
+                    pc: *jump
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_program_replaced_while_paused() {
+        let mut test_vm = VM::new();
+        test_vm.program = Assembler::new()
+            .assemble("inc $0\ninc $0\ninc $0\nhlt\n")
+            .unwrap();
+        test_vm.breakpoints.insert(PIE_HEADER_LENGTH + 8);
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Breakpoint));
+        test_vm.program.truncate(PIE_HEADER_LENGTH + 4);
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidJumpTarget {
+                target: PIE_HEADER_LENGTH as i64 + 8,
+                pc: PIE_HEADER_LENGTH + 4
+            })
+        );
+        test_vm.program.truncate(PIE_HEADER_LENGTH);
+        test_vm.pc = PIE_HEADER_LENGTH;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 1, 1, 0, 32, 2];
+        test_vm.run_once().unwrap();
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::RegisterOutOfRange {
+                register: 32,
+                pc: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![4, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::TruncatedInstruction { pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_aloc_negative_heap() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![17, 0, 0, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOutOfBounds { address: -1, pc: 0 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        test_vm.program = vec![20, 0, 0, 0, 21, 1, 0, 0];
+        test_vm.run_once().unwrap(); // PUSH $0
+        assert_eq!(test_vm.sp, 1);
+        assert_eq!(test_vm.pc, 4);
+        test_vm.run_once().unwrap(); // POP $1
+        assert_eq!(test_vm.sp, 0);
+        assert_eq!(test_vm.registers[1], 42);
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_pop_empty_stack() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![21, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmError::StackUnderflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![
+            22, 0, 8, 0, /* CALL 8 */
+            5, 0, 0, 0, /* HLT */
+            18, 0, 0, 0, /* INC $0 */
+            23, 0, 0, 0, /* RET */
+        ];
+        test_vm.run_once().unwrap(); // CALL
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.sp, 2);
+        assert_eq!(test_vm.bp, 2);
+        test_vm.run_once().unwrap(); // INC
+        test_vm.run_once().unwrap(); // RET
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.sp, 0);
+        assert_eq!(test_vm.bp, 0);
+        assert_eq!(test_vm.run_once(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_opcode_ret_corrupted_frame() {
+        for (saved_bp, expected) in &[
+            (-1, VmError::StackUnderflow { pc: 16 }),
+            (5, VmError::StackUnderflow { pc: 16 }),
+            (5000, VmError::StackOverflow { pc: 16 }),
+        ] {
+            let mut test_vm = VM::new();
+            test_vm.registers[2] = *saved_bp;
+            test_vm.program = vec![
+                22, 0, 8, 0, /* CALL 8 */
+                5, 0, 0, 0, /* HLT */
+                21, 1, 0, 0, /* POP $1 */
+                20, 2, 0, 0, /* PUSH $2 */
+                23, 0, 0, 0, /* RET */
+            ];
+            for _ in 0..3 {
+                test_vm.run_once().unwrap();
+            }
+            assert_eq!(test_vm.run_once(), Err(expected.clone()), "{}", saved_bp);
+        }
+    }
+
+    #[test]
+    fn test_opcode_call_stack_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![22, 0, 0, 0];
+        for _ in 0..STACK_SIZE / 2 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.run_once(), Err(VmError::StackOverflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_run_forward_jump_over_mixed_lengths() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 @done\njmp $0\nload $1 #1\ndone: hlt\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_run_loop_over_mixed_lengths() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $1 #3\nload $2 @loop\nloop: inc $0\nneq $0 $1\njmpe $2\nhlt\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 3);
+    }
+
+    #[test]
+    fn test_run_with_data_section() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble(".data\nhi: .asciiz 'Hi'\nn: .word #7\n.code\nload $0 @n\nhlt\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.ro_data(), &[72, 105, 0, 0, 0, 0, 7]);
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 7 + 12);
+    }
+
+    #[test]
+    fn test_run_starts_at_entry_point() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm
+            .assemble("load $0 #1\nstart: load $1 #2\nhlt\n.entry @start\n")
+            .unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_run_stops_at_end_of_code() {
+        let mut test_vm = VM::new();
+        let mut asm = Assembler::new();
+        test_vm.program = asm.assemble("loop: load $0 #1\n").unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(test_vm.registers[0], 1);
+    }
+
+    #[test]
+    fn test_run_unsupported_version() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![5, 0, 0, 0]);
+        test_vm.program[5] = 2;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnsupportedVersion { version: 2 })
+        );
+    }
 }